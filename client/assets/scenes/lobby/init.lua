@@ -1,2 +1,35 @@
+local network = engine:network()
+local rooms = {}
+
+on_connect = function()
+  network:list_rooms()
+end
+
+on_rooms = function(list)
+  rooms = list
+  for i, room in ipairs(rooms) do
+    local slots = room.max_players > 0 and ("/" .. room.max_players) or ""
+    local lock = room.has_password and " [locked]" or ""
+    lua.print(i .. ": " .. room.name .. " (" .. room.players .. slots .. ")" .. lock)
+  end
+end
+
+on_key_press = function(key)
+  if key == "R" then
+    network:list_rooms()
+    return
+  end
+
+  local index = tonumber(string.match(key, "^Key(%d)$"))
+  if index and rooms[index] then
+    -- the next scene joins, leaving this one would leave the room again
+    engine:load_scene("assets/scripts/init.lua", {room = rooms[index].name})
+  end
+end
+
 on_update = function()
-end
\ No newline at end of file
+end
+
+if network:state() == "connected" then
+  network:list_rooms()
+end
@@ -53,6 +53,8 @@ l_mat:translate({0, 10, 5})
 
 engine:set_scene(main)
 local network = engine:network()
+-- the lobby hands over the room to join
+local room = room or "main"
 local ub = nil
 local bunny = nil
 local user = require("assets/scripts/user")
@@ -60,9 +62,12 @@ local font = methatron.d2.font.new("assets/fonts/UbuntuMono-Regular.ttf")
 -- lua.print("yeha")
 -- font.draw(100, 100, "test string")
 
-on_connect = function()
-  network:join("main")
-  bunny = network:spawn("main", "bunny", nil)
+on_connect = function(reconnected)
+  -- the network joins the last room again by itself
+  if not reconnected then
+    network:join(room)
+  end
+  bunny = network:spawn(room, "bunny", nil)
 
   bunny:get_transform():translate({0.0, 1.0, 3.0})
   ub = user.new(node_target)
@@ -70,10 +75,32 @@ end
 
 on_disconnect = function()
   lua.print("disconnect")
+  ub = nil
+end
+
+on_connection_state = function(state)
+  lua.print("connection " .. state)
+end
+
+on_error = function(code, reason, request)
+  lua.print("server error " .. code .. ": " .. reason)
+end
+
+on_assets_ready = function(scene)
+  lua.print("assets of " .. scene .. " are ready")
+end
+
+on_server_shutdown = function(reason, reconnect_after)
+  lua.print("server shutdown: " .. reason)
+  ub = nil
 end
 
 on_key_press = function(key)
   -- print("press " .. key)
+  if key == "L" then
+    engine:load_scene("assets/scenes/lobby")
+    return
+  end
   if ub then
     ub:on_key_press(key)
   end
@@ -106,4 +133,9 @@ on_update = function()
     l_mat:translate({0,0,-1})
   end
   orb:on_update()
+end
+
+-- coming from another scene the connection is up already, but the room was left
+if network:state() == "connected" then
+  on_connect(false)
 end
\ No newline at end of file
@@ -1,14 +1,15 @@
-
-local m = node:get_transform()
-local border = 150
-
-on_update = function()
-    if is_owner then
-        m:rotate_y(0.03)
-        border = border - 1
-        if border == 0 then
-            engine:network():destroy("main", node:network_id())
-            lua.exit()
+return {
+    on_load = function(self)
+        self.m = self.node:get_transform()
+        self.border = 150
+    end,
+    on_update = function(self, dt)
+        if self.is_owner then
+            self.m:rotate_y(0.03)
+            self.border = self.border - 1
+            if self.border == 0 then
+                engine:network():destroy("main", self.node:network_id())
+            end
         end
     end
-end
\ No newline at end of file
+}
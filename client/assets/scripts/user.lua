@@ -8,7 +8,13 @@ return {
             node = node,
             on_key_press = function(self, key)
                 if key == "Q" then
-                    network:spawn("main", "cube", "assets/scripts/rocket.lua")
+                    -- firing must not stall the key handling until the server answered
+                    local options = { behavior = "assets/scripts/rocket.lua", transform = mat, timeout = 2000 }
+                    network:spawn_async("main", "cube", options, function(rocket, err)
+                        if err then
+                            lua.print("rocket failed: " .. err)
+                        end
+                    end)
                 end
             end,
             on_update = function()
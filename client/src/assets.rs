@@ -0,0 +1,125 @@
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+use shadow_of_truth_common::{assets, AssetInfo};
+
+/// Files shipped with the client, the server paths are relative to it.
+const LOCAL_DIR: &str = "assets";
+
+/// Largest asset the client downloads, larger ones are left out of the room.
+const MAX_ASSET_SIZE: u64 = 64 * 1024 * 1024;
+
+struct Download {
+  info: AssetInfo,
+  data: Vec<u8>,
+}
+
+/// Checks the assets a room requires against the local copies and collects the missing ones from the server.
+/// Downloaded files are stored in a cache next to the executable, keyed by their path on the server.
+pub struct Assets {
+  cache_dir: PathBuf,
+  scene: String,
+  /// Local file of every asset of the current room which is up to date.
+  resolved: HashMap<String, PathBuf>,
+  downloads: HashMap<String, Download>,
+}
+
+fn cache_dir() -> PathBuf {
+  let mut dir = std::env::current_exe().unwrap_or_default();
+  dir.pop();
+  dir.push("cache");
+  dir
+}
+
+fn has_hash(file: &PathBuf, hash: &str) -> bool {
+  match std::fs::read(file) {
+    Ok(data) => assets::hash(&data) == hash,
+    Err(_) => false,
+  }
+}
+
+impl Assets {
+  pub fn new() -> Assets {
+    Assets {
+      cache_dir: cache_dir(),
+      scene: String::new(),
+      resolved: HashMap::new(),
+      downloads: HashMap::new(),
+    }
+  }
+
+  pub fn cache_dir(&self) -> &PathBuf {
+    &self.cache_dir
+  }
+
+  pub fn scene(&self) -> &str {
+    &self.scene
+  }
+
+  /// Starts using the manifest of `scene` and returns the paths which have to be downloaded.
+  pub fn check(&mut self, scene: String, manifest: Vec<AssetInfo>) -> Vec<String> {
+    self.scene = scene;
+    self.resolved.clear();
+    self.downloads.clear();
+
+    for info in manifest {
+      let candidates = [
+        assets::resolve(std::path::Path::new(LOCAL_DIR), &info.path),
+        assets::resolve(&self.cache_dir, &info.path),
+      ];
+
+      match candidates.iter().flatten().find(|file| has_hash(file, &info.hash)) {
+        Some(file) => {
+          self.resolved.insert(info.path.clone(), file.clone());
+        }
+        None if info.size > MAX_ASSET_SIZE => {
+          log::error!("asset {} of {} bytes is too large", info.path, info.size);
+        }
+        None => {
+          // the size is chosen by the server, the data grows with the chunks which actually arrive
+          let download = Download { data: Vec::new(), info };
+          self.downloads.insert(download.info.path.clone(), download);
+        }
+      }
+    }
+
+    self.downloads.keys().cloned().collect()
+  }
+
+  /// Adds a chunk of a download, returns `Ok(true)` once the asset is complete and stored in the cache.
+  pub fn receive(&mut self, path: &str, offset: u64, total: u64, data: &[u8]) -> Result<bool, String> {
+    let download = self.downloads.get_mut(path).ok_or_else(|| format!("unexpected asset {}", path))?;
+
+    if offset != download.data.len() as u64 || total != download.info.size || offset + data.len() as u64 > total {
+      self.downloads.remove(path);
+      return Err(format!("asset {} arrived out of order", path));
+    }
+    download.data.extend_from_slice(data);
+    if (download.data.len() as u64) < total {
+      return Ok(false);
+    }
+
+    let download = self.downloads.remove(path).unwrap();
+    if assets::hash(&download.data) != download.info.hash {
+      return Err(format!("asset {} does not match its hash", path));
+    }
+
+    let file = assets::resolve(&self.cache_dir, path).ok_or_else(|| format!("invalid asset path {}", path))?;
+    if let Some(dir) = file.parent() {
+      std::fs::create_dir_all(dir).map_err(|e| e.to_string())?;
+    }
+    std::fs::write(&file, &download.data).map_err(|e| e.to_string())?;
+    self.resolved.insert(path.to_owned(), file);
+
+    Ok(true)
+  }
+
+  pub fn is_complete(&self) -> bool {
+    self.downloads.is_empty()
+  }
+
+  /// Local file of the asset `path` of the current room.
+  pub fn path(&self, path: &str) -> Option<&PathBuf> {
+    self.resolved.get(path)
+  }
+}
@@ -0,0 +1,42 @@
+use std::collections::VecDeque;
+
+/// Pongs the offset is estimated from.
+const SAMPLES: usize = 8;
+
+/// Estimates the clock of the server from the pongs, the way NTP does.
+pub struct Clock {
+  /// Round trip time and offset of the latest pongs.
+  samples: VecDeque<(u64, i64)>,
+}
+
+impl Clock {
+  pub fn new() -> Clock {
+    Clock {
+      samples: VecDeque::with_capacity(SAMPLES),
+    }
+  }
+
+  /// Adds the pong of a ping sent at `sent` and received at `received`, both local milliseconds.
+  /// The server is assumed to have answered halfway through the round trip.
+  pub fn add(&mut self, sent: u64, received: u64, server_time: u64) {
+    let rtt = received.saturating_sub(sent);
+    let offset = server_time as i64 + (rtt / 2) as i64 - received as i64;
+
+    if self.samples.len() == SAMPLES {
+      self.samples.pop_front();
+    }
+    self.samples.push_back((rtt, offset));
+  }
+
+  /// Milliseconds to add to the local clock, taken from the fastest round trip,
+  /// whose estimate suffers the least from asymmetric delays. `None` before the first pong.
+  pub fn offset(&self) -> Option<i64> {
+    self.samples.iter()
+      .min_by_key(|(rtt, _)| *rtt)
+      .map(|(_, offset)| *offset)
+  }
+
+  pub fn reset(&mut self) {
+    self.samples.clear();
+  }
+}
@@ -0,0 +1,64 @@
+use log::info;
+use serde::Deserialize;
+
+pub const DEFAULT_PORT: u16 = 3000;
+
+fn default_server() -> String {
+  format!("127.0.0.1:{}", DEFAULT_PORT)
+}
+
+fn default_connect() -> bool {
+  true
+}
+
+#[derive(Deserialize)]
+pub struct Config {
+  /// Either `host:port` or `unix:<path>`.
+  #[serde(default = "default_server")]
+  pub server: String,
+  /// Connects on startup, otherwise the scripts decide when and where to connect.
+  #[serde(default = "default_connect")]
+  pub connect: bool,
+  /// Runs changed scripts again while the client is running, for development.
+  #[serde(default)]
+  pub hot_reload: bool,
+}
+
+impl Default for Config {
+  fn default() -> Config {
+    Config {
+      server: default_server(),
+      connect: default_connect(),
+      hot_reload: false,
+    }
+  }
+}
+
+/// Reads `config.toml` when present, `--server <address>` on the command line overrides the server
+/// and `--hot-reload` enables reloading.
+pub fn load() -> Result<Config, Box<dyn std::error::Error>> {
+  let path = std::path::Path::new("config.toml");
+
+  let mut config = if path.exists() {
+    info!("load config");
+
+    let data = std::fs::read_to_string(path)?;
+    toml::from_str(&data)?
+  }
+  else {
+    Config::default()
+  };
+
+  let mut args = std::env::args().skip(1);
+  while let Some(arg) = args.next() {
+    if arg == "--server" {
+      config.server = args.next().ok_or("--server needs an address")?;
+      config.connect = true;
+    }
+    else if arg == "--hot-reload" {
+      config.hot_reload = true;
+    }
+  }
+
+  Ok(config)
+}
@@ -1,15 +1,23 @@
 use crate::network::{self, Network};
 
-use std::collections::BTreeSet;
-use std::sync::{Arc, RwLock};
+use std::collections::{BTreeSet, HashMap};
+use std::path::Path;
+use std::sync::{Arc, RwLock, atomic::{AtomicBool, Ordering}};
 
-use crate::methatron::scene::{Scene, SceneUserData};
+use crate::lua::{self, EnvValue};
+use crate::methatron::{
+  behavior,
+  pump,
+  scene::{Scene, SceneUserData},
+};
 
 pub struct ImplContext {
   pub scene: Option<Scene>,
   pub keys_down: BTreeSet<String>,
   pub mouse_position: [f64; 2],
   pub network: Network,
+  /// Running flags of the scripts of the current scene.
+  pub scripts: Vec<Arc<AtomicBool>>,
 }
 
 pub type Context = Arc<RwLock<ImplContext>>;
@@ -27,6 +35,7 @@ pub fn get() -> Context {
         keys_down: BTreeSet::new(),
         mouse_position: [0.0, 0.0],
         network: network::new(),
+        scripts: Vec::new(),
       }));
       CONTEXT = Some(p.clone());
       p
@@ -34,6 +43,38 @@ pub fn get() -> Context {
   }
 }
 
+/// Stops the scripts and behaviors of the current scene, leaves its room, unloads its nodes and resources
+/// and runs the next scene with the globals of `env`, which is either a script or a directory with an `init.lua`.
+pub fn load_scene(ctx: &Context, path: &str, env: HashMap<String, EnvValue>) {
+  let path = Path::new(path);
+  let init = if path.is_dir() { path.join("init.lua") } else { path.to_owned() };
+  log::info!("load scene {}", init.display());
+
+  let (scripts, scene, network) = {
+    let mut c = ctx.write().unwrap();
+    (std::mem::take(&mut c.scripts), c.scene.take(), c.network.clone())
+  };
+
+  // every script ends after its current call
+  for running in scripts {
+    running.store(false, Ordering::SeqCst);
+  }
+  behavior::get().clear();
+  // the entities of this client would stay in the room without anyone moving them
+  network.leave_room();
+  network.forget_nodes();
+
+  // gl resources have to be released on the gl thread
+  if let Some(scene) = scene {
+    let mut scene = Some(scene);
+    pump::get().exec(move || {
+      scene.take();
+    });
+  }
+
+  lua::spawn(ctx.clone(), init.to_string_lossy().into_owned(), env);
+}
+
 pub struct ContextUserData(pub Context);
 
 impl mlua::UserData for ContextUserData {
@@ -74,7 +115,15 @@ impl mlua::UserData for ContextUserData {
         Ok(ctx.network.clone())
       });
 
-      methods.add_method("execute", |_, this, filename: String| {
+      // runs the script with the globals of `env` next to the calling one, until the scene changes
+      methods.add_method("execute", |_, this, (filename, env): (String, Option<HashMap<String, EnvValue>>)| {
+        lua::spawn(this.0.clone(), filename, env.unwrap_or_default());
+        Ok(())
+      });
+
+      // the globals of `env` hand over state, like the room to join
+      methods.add_method("load_scene", |_, this, (path, env): (String, Option<HashMap<String, EnvValue>>)| {
+        load_scene(&this.0, &path, env.unwrap_or_default());
         Ok(())
       });
    }
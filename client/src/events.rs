@@ -1,15 +1,105 @@
+use std::collections::HashSet;
+use std::sync::{Arc, Mutex};
+
+use shadow_of_truth_common::{LockstepConfig, Offender, PlayerInput, RoomInfo};
+
+#[derive(Clone)]
 pub enum Events {
-    Connected,
+    /// Logged in, `reconnected` after the connection was lost.
+    Connected{reconnected: bool},
     Disconnected,
+    /// Name of the new `ConnectionState`.
+    ConnectionState(String),
+    Rooms(Vec<RoomInfo>),
+    /// Users whose movements the server corrected, only admins get them.
+    Offenders(Vec<Offender>),
+    Error{code: String, reason: String, in_reply_to: Option<u64>},
+    ServerShutdown{reason: String, reconnect_after: Option<u64>},
+    /// Every asset of the room is available locally.
+    AssetsReady(String),
+    /// Joined a room in lockstep, `tick` is the next one the server sends.
+    Lockstep{scene: String, tick: u64, config: LockstepConfig},
+    /// Every input of `tick`, which can be simulated now.
+    Tick{scene: String, tick: u64, inputs: Vec<PlayerInput>},
+    /// The players reported different checksums for `tick`.
+    Desync{scene: String, tick: u64, checksums: Vec<(String, u32)>},
     KeyPressed(String),
     KeyReleased(String),
     MouseWheel(f32),
 }
 
+/// Names of the lua functions handling the events.
+pub const HANDLERS: [&str; 14] = [
+  "on_connect", "on_disconnect", "on_connection_state", "on_rooms", "on_offenders", "on_error", "on_server_shutdown", "on_assets_ready",
+  "on_lockstep", "on_tick", "on_desync", "on_key_press", "on_key_release", "on_mouse_wheel",
+];
+
+/// Handlers scripts of other clients may have, they must not learn the keys of the player or how the connection goes.
+pub const SANDBOXED_HANDLERS: [&str; 4] = ["on_assets_ready", "on_lockstep", "on_tick", "on_desync"];
+
+impl Events {
+  /// Name of the lua function handling the event.
+  pub fn handler(&self) -> &'static str {
+    match self {
+      Events::Connected{..} => "on_connect",
+      Events::Disconnected => "on_disconnect",
+      Events::ConnectionState(_) => "on_connection_state",
+      Events::Rooms(_) => "on_rooms",
+      Events::Offenders(_) => "on_offenders",
+      Events::Error{..} => "on_error",
+      Events::ServerShutdown{..} => "on_server_shutdown",
+      Events::AssetsReady(_) => "on_assets_ready",
+      Events::Lockstep{..} => "on_lockstep",
+      Events::Tick{..} => "on_tick",
+      Events::Desync{..} => "on_desync",
+      Events::KeyPressed(_) => "on_key_press",
+      Events::KeyReleased(_) => "on_key_release",
+      Events::MouseWheel(_) => "on_mouse_wheel",
+    }
+  }
+}
+
+/// Events a subscriber may fall behind before further events are dropped for it.
+const QUEUE_SIZE: usize = 100;
+
+struct Subscriber {
+  sender: crossbeam_channel::Sender<Events>,
+  filter: Box<dyn Fn(&Events) -> bool + Send>,
+}
+
+/// Delivers every published event to each subscriber whose filter accepts it.
 #[derive(Clone)]
 pub struct EventPump {
-  pub sender: crossbeam_channel::Sender<Events>, 
-  pub receiver: crossbeam_channel::Receiver<Events>,
+  subscribers: Arc<Mutex<Vec<Subscriber>>>,
+}
+
+impl EventPump {
+  /// Drop the receiver to unsubscribe.
+  pub fn subscribe<F>(&self, filter: F) -> crossbeam_channel::Receiver<Events>
+  where F: Fn(&Events) -> bool + Send + 'static
+  {
+    let (sender, receiver) = crossbeam_channel::bounded(QUEUE_SIZE);
+    self.subscribers.lock().unwrap().push(Subscriber { sender, filter: Box::new(filter) });
+    receiver
+  }
+
+  /// Never blocks, a subscriber which does not keep up misses events.
+  pub fn publish(&self, event: Events) {
+    self.subscribers.lock().unwrap().retain(|subscriber| {
+      if !(subscriber.filter)(&event) {
+        return true;
+      }
+
+      match subscriber.sender.try_send(event.clone()) {
+        Ok(()) => true,
+        Err(crossbeam_channel::TrySendError::Full(_)) => {
+          log::warn!("drop {} of a busy subscriber", event.handler());
+          true
+        }
+        Err(crossbeam_channel::TrySendError::Disconnected(_)) => false,
+      }
+    });
+  }
 }
 
 static mut EVENT_PUMP: Option<EventPump> = None;
@@ -20,10 +110,42 @@ pub fn get() -> EventPump {
       p.clone()
     }
     else {
-      let (s, r) = crossbeam_channel::bounded::<Events>(100);
-      let p = EventPump {sender: s, receiver: r};
+      let p = EventPump {subscribers: Arc::new(Mutex::new(Vec::new()))};
       EVENT_PUMP = Some(p.clone());
       p
     }
   }
-}
\ No newline at end of file
+}
+
+/// The handlers of a script which receive events, the script changes them while it runs.
+#[derive(Clone)]
+pub struct Subscription {
+  handlers: Arc<Mutex<HashSet<&'static str>>>,
+  allowed: &'static [&'static str],
+}
+
+impl Subscription {
+  /// Starts without handlers, only the `allowed` ones can be added.
+  pub fn new(allowed: &'static [&'static str]) -> (Subscription, crossbeam_channel::Receiver<Events>) {
+    let handlers = Arc::new(Mutex::new(HashSet::new()));
+    let receiver = {
+      let handlers: Arc<Mutex<HashSet<&'static str>>> = handlers.clone();
+      get().subscribe(move |event| handlers.lock().unwrap().contains(event.handler()))
+    };
+    (Subscription { handlers, allowed }, receiver)
+  }
+
+  pub fn add(&self, handler: &str) -> Result<(), String> {
+    match self.allowed.iter().find(|name| **name == handler) {
+      Some(name) => {
+        self.handlers.lock().unwrap().insert(name);
+        Ok(())
+      }
+      None => Err(format!("no events for {}", handler)),
+    }
+  }
+
+  pub fn remove(&self, handler: &str) {
+    self.handlers.lock().unwrap().remove(handler);
+  }
+}
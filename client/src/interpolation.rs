@@ -0,0 +1,79 @@
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+use crate::methatron::math::matrix;
+
+const MAX_SNAPSHOTS: usize = 32;
+
+struct Snapshot {
+  time: u64,
+  transform: [f32; 16],
+}
+
+/// Keeps the latest server transforms of a remote node and renders them a fixed delay in the past,
+/// so the node moves smoothly between updates instead of snapping.
+pub struct InterpolationBuffer {
+  snapshots: VecDeque<Snapshot>,
+  received: Instant,
+}
+
+impl InterpolationBuffer {
+  pub fn new() -> InterpolationBuffer {
+    InterpolationBuffer {
+      snapshots: VecDeque::new(),
+      received: Instant::now(),
+    }
+  }
+
+  pub fn push(&mut self, time: u64, transform: [f32; 16]) {
+    if let Some(last) = self.snapshots.back() {
+      if time <= last.time {
+        // out of order or duplicate
+        return;
+      }
+    }
+
+    self.snapshots.push_back(Snapshot { time, transform });
+    self.received = Instant::now();
+
+    while self.snapshots.len() > MAX_SNAPSHOTS {
+      self.snapshots.pop_front();
+    }
+  }
+
+  /// Returns the transform at `delay` behind the estimated server time.
+  /// When no newer snapshot arrived yet, the motion of the last two snapshots is extrapolated for at most `max_extrapolation`.
+  pub fn sample(&self, delay: Duration, max_extrapolation: Duration) -> Option<[f32; 16]> {
+    let newest = self.snapshots.back()?;
+    let server_now = newest.time + self.received.elapsed().as_millis() as u64;
+    let render_time = server_now.saturating_sub(delay.as_millis() as u64);
+
+    if render_time >= newest.time {
+      if self.snapshots.len() < 2 {
+        return Some(newest.transform);
+      }
+
+      let previous = &self.snapshots[self.snapshots.len() - 2];
+      let ahead = (render_time - newest.time).min(max_extrapolation.as_millis() as u64);
+      let span = (newest.time - previous.time) as f32;
+      let percent = 1.0 + ahead as f32 / span;
+
+      return Some(matrix::interpolate(&previous.transform, &newest.transform, percent));
+    }
+
+    let mut older = &self.snapshots[0];
+    if render_time <= older.time {
+      return Some(older.transform);
+    }
+
+    for newer in self.snapshots.iter().skip(1) {
+      if newer.time >= render_time {
+        let percent = (render_time - older.time) as f32 / (newer.time - older.time) as f32;
+        return Some(matrix::interpolate(&older.transform, &newer.transform, percent));
+      }
+      older = newer;
+    }
+
+    Some(newest.transform)
+  }
+}
@@ -1,11 +1,22 @@
+use std::collections::HashMap;
 use std::error::Error;
+use std::mem::ManuallyDrop;
+use std::ops::Deref;
+use std::os::raw::{c_char, c_void};
+use std::path::{Path, PathBuf};
 use std::sync::{
-  atomic::{AtomicBool, Ordering},
+  atomic::{AtomicBool, AtomicU64, Ordering},
   Arc,
 };
 
-use crate::methatron;
+use crate::methatron::{
+  self,
+  node::{Node, NodeUserData},
+  scene::{Scene, SceneUserData},
+};
 use crate::context;
+use crate::events::{self, Events};
+use crate::reload;
 use crate::tracer;
 
 fn lua_env(lua: &mlua::Lua) -> mlua::Result<Arc<AtomicBool>> {
@@ -34,25 +45,461 @@ fn lua_env(lua: &mlua::Lua) -> mlua::Result<Arc<AtomicBool>> {
   Ok(running)
 }
 
-pub fn execute<F>(ctx: context::Context, filename: &str, env: F) -> Result<(), Box<dyn Error>> 
+/// Limits for scripts which were named by other clients, they must not be able to harm the player.
+pub struct Sandbox {
+  /// Directories the script has to be in.
+  pub roots: Vec<PathBuf>,
+  /// Instructions each call into the script may take.
+  pub instructions: u64,
+  /// Bytes the lua state may allocate.
+  pub memory: usize,
+}
+
+/// Instructions between two checks of the instruction limit.
+const HOOK_INTERVAL: u32 = 1000;
+
+/// Makes the instruction limit fatal, the functions catching errors pass it on once the budget is spent.
+/// The chunk gets a function telling whether the budget is spent.
+const SANDBOX_GUARDS: &str = r#"
+local spent = ...
+local raw_pcall, raw_xpcall, error = pcall, xpcall, error
+
+local function rethrow(ok, ...)
+  if not ok and spent() then
+    error("script exceeded its instruction limit", 0)
+  end
+  return ok, ...
+end
+
+_G.pcall = function(...) return rethrow(raw_pcall(...)) end
+_G.xpcall = function(...) return rethrow(raw_xpcall(...)) end
+"#;
+
+/// The parts of the lua api mlua does not expose, sandboxed states are created with them.
+mod ffi {
+  use std::os::raw::{c_char, c_int, c_void};
+
+  pub enum State {}
+
+  pub type CFunction = unsafe extern "C" fn(*mut State) -> c_int;
+  pub type Alloc = unsafe extern "C" fn(*mut c_void, *mut c_void, usize, usize) -> *mut c_void;
+
+  pub const LUAJIT_MODE_ENGINE: c_int = 0;
+  pub const LUAJIT_MODE_OFF: c_int = 0;
+
+  extern "C" {
+    pub fn lua_newstate(f: Alloc, ud: *mut c_void) -> *mut State;
+    pub fn lua_close(state: *mut State);
+    pub fn lua_pushcclosure(state: *mut State, f: CFunction, n: c_int);
+    pub fn lua_pushstring(state: *mut State, s: *const c_char);
+    pub fn lua_call(state: *mut State, nargs: c_int, nresults: c_int);
+    pub fn luaJIT_setmode(state: *mut State, idx: c_int, mode: c_int) -> c_int;
+
+    pub fn luaopen_base(state: *mut State) -> c_int;
+    pub fn luaopen_table(state: *mut State) -> c_int;
+    pub fn luaopen_string(state: *mut State) -> c_int;
+    pub fn luaopen_math(state: *mut State) -> c_int;
+    pub fn luaopen_bit(state: *mut State) -> c_int;
+  }
+}
+
+/// Bytes allocated by a sandboxed state.
+struct Allocations {
+  used: usize,
+  limit: usize,
+}
+
+/// Fails like an exhausted system allocator once the limit is reached, lua raises a memory error then.
+/// Checking the limit from a hook is too late, a single call like `string.rep` allocates gigabytes.
+unsafe extern "C" fn limited_alloc(ud: *mut c_void, ptr: *mut c_void, osize: usize, nsize: usize) -> *mut c_void {
+  let allocations = &mut *(ud as *mut Allocations);
+  // `osize` is only the size of the block when there is one
+  let osize = if ptr.is_null() { 0 } else { osize };
+
+  if nsize == 0 {
+    libc::free(ptr);
+    allocations.used -= osize;
+    return std::ptr::null_mut();
+  }
+  if nsize > osize && allocations.used + (nsize - osize) > allocations.limit {
+    return std::ptr::null_mut();
+  }
+
+  let block = libc::realloc(ptr, nsize);
+  if !block.is_null() {
+    allocations.used = allocations.used - osize + nsize;
+  }
+  block
+}
+
+/// A lua state, a sandboxed one is closed before its allocations.
+pub struct Lua {
+  lua: ManuallyDrop<mlua::Lua>,
+  sandboxed: Option<(*mut ffi::State, *mut Allocations)>,
+}
+
+impl Deref for Lua {
+  type Target = mlua::Lua;
+
+  fn deref(&self) -> &mlua::Lua {
+    &self.lua
+  }
+}
+
+impl Drop for Lua {
+  fn drop(&mut self) {
+    unsafe {
+      ManuallyDrop::drop(&mut self.lua);
+      if let Some((state, allocations)) = self.sandboxed {
+        // whether mlua closes a state it did not create depends on its version, a closed state freed everything
+        if (*allocations).used > 0 {
+          ffi::lua_close(state);
+        }
+        drop(Box::from_raw(allocations));
+      }
+    }
+  }
+}
+
+impl Sandbox {
+  /// `None` when `filename` is not inside one of the allowed roots.
+  pub fn check_path(&self, filename: &str) -> Option<PathBuf> {
+    let file = Path::new(filename).canonicalize().ok()?;
+    self.roots.iter()
+      .filter_map(|root| root.canonicalize().ok())
+      .any(|root| file.starts_with(root))
+      .then(|| file)
+  }
+
+  /// Creates a state without access to the filesystem, the OS or native code.
+  fn create_lua(&self, budget: Arc<AtomicU64>) -> mlua::Result<Lua> {
+    let allocations = Box::into_raw(Box::new(Allocations { used: 0, limit: self.memory }));
+    let lua = unsafe {
+      let state = ffi::lua_newstate(limited_alloc, allocations as *mut c_void);
+      // LuaJIT without GC64 only runs with its own allocator, such a build can not run scripts of other clients
+      if state.is_null() {
+        drop(Box::from_raw(allocations));
+        return Err(methatron::error::to_lua_err("lua does not support the allocator of the sandbox"));
+      }
+
+      // the instruction hook is not called from compiled code
+      ffi::luaJIT_setmode(state, 0, ffi::LUAJIT_MODE_ENGINE | ffi::LUAJIT_MODE_OFF);
+
+      let libs: [(&[u8], ffi::CFunction); 5] = [
+        (b"\0", ffi::luaopen_base),
+        (b"table\0", ffi::luaopen_table),
+        (b"string\0", ffi::luaopen_string),
+        (b"math\0", ffi::luaopen_math),
+        (b"bit\0", ffi::luaopen_bit),
+      ];
+      for (name, open) in libs.iter() {
+        ffi::lua_pushcclosure(state, *open, 0);
+        ffi::lua_pushstring(state, name.as_ptr() as *const c_char);
+        ffi::lua_call(state, 1, 0);
+      }
+
+      Lua {
+        lua: ManuallyDrop::new(mlua::Lua::init_from_ptr(state as *mut _)),
+        sandboxed: Some((state, allocations)),
+      }
+    };
+
+    let globals = lua.globals();
+    // the instruction hook is not called inside coroutines, so a coroutine could loop forever
+    for name in ["dofile", "loadfile", "load", "loadstring", "require", "module", "getfenv", "setfenv", "coroutine"].iter() {
+      globals.set(*name, mlua::Value::Nil)?;
+    }
+
+    let instructions = self.instructions;
+    {
+      let budget = budget.clone();
+      let spent = lua.create_function(move |_, ()| Ok(budget.load(Ordering::SeqCst) > instructions))?;
+      lua.load(SANDBOX_GUARDS).call::<_, ()>(spent)?;
+    }
+
+    let triggers = mlua::HookTriggers { every_nth_instruction: Some(HOOK_INTERVAL), ..Default::default() };
+    lua.set_hook(triggers, move |_, _| {
+      if budget.fetch_add(HOOK_INTERVAL as u64, Ordering::SeqCst) > instructions {
+        return Err(methatron::error::to_lua_err("script exceeded its instruction limit"));
+      }
+      Ok(())
+    })?;
+
+    Ok(lua)
+  }
+}
+
+/// Tables nested deeper are not handed to another script, which also stops at cycles.
+const MAX_ENV_DEPTH: usize = 16;
+
+/// A value handed from one script to another, they run in different lua states.
+#[derive(Clone)]
+pub enum EnvValue {
+  Nil,
+  Boolean(bool),
+  Integer(i64),
+  Number(f64),
+  String(Vec<u8>),
+  Table(Vec<(EnvValue, EnvValue)>),
+  Node(Node),
+  Scene(Scene),
+}
+
+impl EnvValue {
+  fn from_value(value: mlua::Value, depth: usize) -> mlua::Result<EnvValue> {
+    Ok(match value {
+      mlua::Value::Nil => EnvValue::Nil,
+      mlua::Value::Boolean(b) => EnvValue::Boolean(b),
+      mlua::Value::Integer(i) => EnvValue::Integer(i),
+      mlua::Value::Number(n) => EnvValue::Number(n),
+      mlua::Value::String(s) => EnvValue::String(s.as_bytes().to_vec()),
+      mlua::Value::Table(_) if depth >= MAX_ENV_DEPTH => {
+        return Err(methatron::error::to_lua_err("tables handed to another script are nested too deep"));
+      }
+      mlua::Value::Table(t) => {
+        let mut pairs = Vec::new();
+        for pair in t.pairs::<mlua::Value, mlua::Value>() {
+          let (key, value) = pair?;
+          pairs.push((EnvValue::from_value(key, depth + 1)?, EnvValue::from_value(value, depth + 1)?));
+        }
+        EnvValue::Table(pairs)
+      }
+      mlua::Value::UserData(ud) => {
+        let node = ud.borrow::<NodeUserData>().map(|n| n.node.clone());
+        let scene = ud.borrow::<SceneUserData>().map(|s| s.scene.clone());
+        match (node, scene) {
+          (Ok(node), _) => EnvValue::Node(node),
+          (_, Ok(scene)) => EnvValue::Scene(scene),
+          _ => return Err(methatron::error::to_lua_err("only nodes and scenes can be handed to another script")),
+        }
+      }
+      value => {
+        return Err(methatron::error::to_lua_err(&format!("a {} can not be handed to another script", value.type_name())));
+      }
+    })
+  }
+}
+
+impl<'lua> mlua::FromLua<'lua> for EnvValue {
+  fn from_lua(value: mlua::Value<'lua>, _: &'lua mlua::Lua) -> mlua::Result<EnvValue> {
+    EnvValue::from_value(value, 0)
+  }
+}
+
+impl<'lua> mlua::ToLua<'lua> for EnvValue {
+  fn to_lua(self, lua: &'lua mlua::Lua) -> mlua::Result<mlua::Value<'lua>> {
+    use mlua::ToLua;
+
+    match self {
+      EnvValue::Nil => Ok(mlua::Value::Nil),
+      EnvValue::Boolean(b) => Ok(mlua::Value::Boolean(b)),
+      EnvValue::Integer(i) => Ok(mlua::Value::Integer(i)),
+      EnvValue::Number(n) => Ok(mlua::Value::Number(n)),
+      EnvValue::String(s) => Ok(mlua::Value::String(lua.create_string(&s)?)),
+      EnvValue::Table(pairs) => Ok(mlua::Value::Table(lua.create_table_from(pairs)?)),
+      EnvValue::Node(node) => NodeUserData { node }.to_lua(lua),
+      EnvValue::Scene(scene) => SceneUserData { scene }.to_lua(lua),
+    }
+  }
+}
+
+/// Runs `filename` on its own thread with the globals of `env`, the script ends when the scene changes.
+pub fn spawn(ctx: context::Context, filename: String, env: HashMap<String, EnvValue>) {
+  // registered before the thread starts, so a scene change right after ends the script as well
+  let scene = Arc::new(AtomicBool::new(true));
+  ctx.write().unwrap().scripts.push(scene.clone());
+
+  std::thread::spawn(move || {
+    let result = execute(ctx, &filename, scene, |globals| {
+      for (name, value) in env.iter() {
+        globals.set(name.as_str(), value.clone())?;
+      }
+      Ok(())
+    });
+    if let Err(e) = result {
+      log::error!("{} {}", filename, e);
+    }
+  });
+}
+
+/// Runs `filename` until it exits or `scene` is cleared.
+pub fn execute<F>(ctx: context::Context, filename: &str, scene: Arc<AtomicBool>, env: F) -> Result<(), Box<dyn Error>> 
 where F: Fn(&mlua::Table) -> mlua::Result<()>
 {
-  let lua = mlua::Lua::new();
+  run(ctx, filename, scene, env)
+}
+
+/// Creates a state with the engine api, restricted by `sandbox` when given.
+/// `budget` counts the instructions of a sandboxed state, reset it before every call into the state.
+pub fn new_state(ctx: context::Context, sandbox: Option<&Sandbox>, budget: Arc<AtomicU64>) -> mlua::Result<(Lua, Arc<AtomicBool>)> {
+  let lua = match sandbox {
+    Some(sandbox) => sandbox.create_lua(budget)?,
+    None => Lua { lua: ManuallyDrop::new(mlua::Lua::new()), sandboxed: None },
+  };
   let meth = lua.create_table()?;
 
-  methatron::scene::load_module(&lua, &meth)?;
   methatron::node::load_module(&lua, &meth)?;
   methatron::math::load_module(&lua, &meth)?;
   methatron::light::load_module(&lua, &meth)?;
-  methatron::d2::load_module(&lua, &meth)?;
+  // scripts of other clients must not open files, like models and fonts, nor attach more scripts
+  if sandbox.is_none() {
+    methatron::scene::load_module(&lua, &meth)?;
+    methatron::d2::load_module(&lua, &meth)?;
+    methatron::behavior::load_module(&lua, &meth)?;
+  }
+
+  let running = lua_env(&lua)?;
 
   let globals = lua.globals();
+  globals.set("methatron", meth)?;
+  // the engine runs scripts without a sandbox and reaches the network, scripts of other clients get neither
+  if sandbox.is_none() {
+    globals.set("engine", context::ContextUserData(ctx))?;
+  }
+
+  Ok((lua, running))
+}
+
+/// Arguments of the handler of `event`.
+pub fn event_args(lua: &mlua::Lua, event: Events) -> mlua::Result<Vec<mlua::Value>> {
+  use mlua::ToLuaMulti;
+
+  let args = match event {
+    Events::Connected{reconnected} => reconnected.to_lua_multi(lua)?,
+    Events::Disconnected => mlua::MultiValue::new(),
+    Events::ConnectionState(state) => state.to_lua_multi(lua)?,
+    Events::Rooms(rooms) => {
+      let list = lua.create_table()?;
+      for (i, r) in rooms.into_iter().enumerate() {
+        let room = lua.create_table()?;
+        room.set("name", r.name)?;
+        room.set("players", r.players)?;
+        room.set("max_players", r.max_players)?;
+        room.set("has_password", r.has_password)?;
+        room.set("persistent", r.persistent)?;
+        if let Some(config) = r.lockstep {
+          let lockstep = lua.create_table()?;
+          lockstep.set("tick_ms", config.tick_ms)?;
+          lockstep.set("input_delay", config.input_delay)?;
+          room.set("lockstep", lockstep)?;
+        }
+        list.set(i + 1, room)?;
+      }
+      list.to_lua_multi(lua)?
+    }
+    Events::Offenders(offenders) => {
+      let list = lua.create_table()?;
+      for (i, o) in offenders.into_iter().enumerate() {
+        let offender = lua.create_table()?;
+        offender.set("id", o.id)?;
+        offender.set("violations", o.violations)?;
+        offender.set("flagged", o.flagged)?;
+        list.set(i + 1, offender)?;
+      }
+      list.to_lua_multi(lua)?
+    }
+    Events::Error{code, reason, in_reply_to} => (code, reason, in_reply_to).to_lua_multi(lua)?,
+    Events::ServerShutdown{reason, reconnect_after} => (reason, reconnect_after).to_lua_multi(lua)?,
+    Events::AssetsReady(scene) => scene.to_lua_multi(lua)?,
+    Events::Lockstep{scene, tick, config} => (scene, tick, config.tick_ms, config.input_delay).to_lua_multi(lua)?,
+    Events::Tick{scene, tick, inputs} => {
+      let list = lua.create_table()?;
+      for (i, input) in inputs.into_iter().enumerate() {
+        let entry = lua.create_table()?;
+        entry.set("player", input.player)?;
+        entry.set("data", lua.create_string(&input.data)?)?;
+        list.set(i + 1, entry)?;
+      }
+      (scene, tick, list).to_lua_multi(lua)?
+    }
+    Events::Desync{scene, tick, checksums} => {
+      let players = lua.create_table()?;
+      for (player, checksum) in checksums {
+        players.set(player, checksum)?;
+      }
+      (scene, tick, players).to_lua_multi(lua)?
+    }
+    Events::KeyPressed(key) => key.to_lua_multi(lua)?,
+    Events::KeyReleased(key) => key.to_lua_multi(lua)?,
+    Events::MouseWheel(pos) => pos.to_lua_multi(lua)?,
+  };
+
+  Ok(args.into_vec())
+}
+
+/// Adds `subscribe` and `unsubscribe` to `table`, they take the names of handlers which should (not) receive events.
+pub fn subscription_api(lua: &mlua::Lua, table: &mlua::Table, subscription: &events::Subscription) -> mlua::Result<()> {
+  // called as methods of behaviors as well, which passes `self` ahead of the names
+  fn names(values: mlua::Variadic<mlua::Value>) -> mlua::Result<Vec<String>> {
+    let mut names = Vec::new();
+    for value in values.iter() {
+      if let mlua::Value::String(name) = value {
+        names.push(name.to_str()?.to_owned());
+      }
+    }
+    Ok(names)
+  }
+
+  let s = subscription.clone();
+  table.set("subscribe", lua.create_function(move |_, values: mlua::Variadic<mlua::Value>| {
+    for name in names(values)? {
+      s.add(&name).map_err(|e| methatron::error::to_lua_err(&e))?;
+    }
+    Ok(())
+  })?)?;
+
+  let s = subscription.clone();
+  table.set("unsubscribe", lua.create_function(move |_, values: mlua::Variadic<mlua::Value>| {
+    for name in names(values)? {
+      s.remove(&name);
+    }
+    Ok(())
+  })?)?;
+
+  Ok(())
+}
 
+/// Subscribes to the handlers `table` defines, leaving out those the script may not have.
+pub fn subscribe_defined(table: &mlua::Table, subscription: &events::Subscription) -> mlua::Result<()> {
+  for name in events::HANDLERS.iter() {
+    if table.get::<_, Option<mlua::Function>>(*name)?.is_some() {
+      if let Err(e) = subscription.add(name) {
+        log::warn!("{}", e);
+      }
+    }
+  }
+  Ok(())
+}
+
+/// Runs the script again, the value returned by `on_unload` is handed to `on_reload` of the new version.
+fn reload_script(lua: &mlua::Lua, filename: &str, changed: &[PathBuf]) -> Result<(), Box<dyn Error>> {
+  reload::forget_modules(lua, changed)?;
+
+  let globals = lua.globals();
+  let state = match globals.get::<_, Option<mlua::Function>>("on_unload")? {
+    Some(on_unload) => on_unload.call::<_, mlua::Value>(())?,
+    None => mlua::Value::Nil,
+  };
+
+  let src = std::fs::read(filename)?;
+  lua.load(&src).set_name(filename)?.exec()?;
+
+  if let Some(on_reload) = globals.get::<_, Option<mlua::Function>>("on_reload")? {
+    on_reload.call::<_, ()>(state)?;
+  }
+  Ok(())
+}
+
+fn run<F>(ctx: context::Context, filename: &str, scene: Arc<AtomicBool>, env: F) -> Result<(), Box<dyn Error>>
+where F: Fn(&mlua::Table) -> mlua::Result<()>
+{
+  let (lua, running) = new_state(ctx.clone(), None, Arc::new(AtomicU64::new(0)))?;
+
+  let globals = lua.globals();
   env(&globals)?;
-  let running = lua_env(&lua)?;
 
-  globals.set("methatron", meth)?;
-  globals.set("engine", context::ContextUserData(ctx.clone()))?;
+  let (subscription, events) = events::Subscription::new(&events::HANDLERS);
+  subscription_api(&lua, &globals.get("lua")?, &subscription)?;
 
   {
     let src = std::fs::read(filename)?;
@@ -61,34 +508,36 @@ where F: Fn(&mlua::Table) -> mlua::Result<()>
   }
 
   if globals.contains_key("on_update")? {
-    let on_update: mlua::Function = globals.get("on_update")?;
-    let events = crate::events::get();
+    let mut on_update: mlua::Function = globals.get("on_update")?;
+    // handlers defined later on have to be added with `lua.subscribe`
+    subscribe_defined(&globals, &subscription)?;
+    let mut watcher = reload::Watcher::new();
+    watcher.watch(Path::new(filename));
 
-    while running.load(Ordering::SeqCst) {
+    while running.load(Ordering::SeqCst) && scene.load(Ordering::SeqCst) {
       let start = std::time::Instant::now();
 
-      while let Ok(event) = events.receiver.try_recv() {
-        let cb: Option<mlua::Function> = match event {
-          crate::events::Events::Connected => {
-            globals.get("on_connect").ok()
-          }
-          crate::events::Events::Disconnected => {
-            globals.get("on_connect").ok()
-          }
-          crate::events::Events::KeyPressed(key) => {
-            globals.get("on_key_press").ok().map(|f: mlua::Function| f.bind(key).unwrap())
-          }
-          crate::events::Events::KeyReleased(key) => {
-            globals.get("on_key_release").ok().map(|f: mlua::Function| f.bind(key).unwrap())
-          }
-          crate::events::Events::MouseWheel(pos) => {
-            globals.get("on_mouse_wheel").ok().map(|f: mlua::Function| f.bind(pos).unwrap())
-          }
-          _ => {None}
-        };
+      while let Ok(event) = events.try_recv() {
+        if let Some(handler) = globals.get::<_, Option<mlua::Function>>(event.handler())? {
+          handler.call::<_, ()>(mlua::MultiValue::from_vec(event_args(&lua, event)?))?;
+        }
+      }
+
+      crate::network::call_spawn_callbacks(&lua)?;
 
-        if let Some(cb) = cb {
-          cb.call(())?;
+      if watcher.is_due() {
+        reload::watch_modules(&lua, &mut watcher)?;
+        let changed = watcher.changed();
+        if !changed.is_empty() {
+          log::info!("reload {}", filename);
+          match reload_script(&lua, filename, &changed) {
+            Ok(()) => {
+              on_update = globals.get("on_update")?;
+              subscribe_defined(&globals, &subscription)?;
+            }
+            // the previous version goes on until the script is fixed
+            Err(e) => log::error!("reload {} {}", filename, e),
+          }
         }
       }
 
@@ -102,4 +551,39 @@ where F: Fn(&mlua::Table) -> mlua::Result<()>
   }
 
   Ok(())
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn sandbox() -> (Lua, Arc<AtomicU64>) {
+    let sandbox = Sandbox { roots: Vec::new(), instructions: 100_000, memory: 16 * 1024 * 1024 };
+    let budget = Arc::new(AtomicU64::new(0));
+    (sandbox.create_lua(budget.clone()).unwrap(), budget)
+  }
+
+  #[test]
+  fn instruction_limit_can_not_be_caught() {
+    let (lua, budget) = sandbox();
+    assert!(lua.load("while true do pcall(function() while true do end end) end").exec().is_err());
+
+    budget.store(0, Ordering::SeqCst);
+    assert!(lua.load("while true do xpcall(function() while true do end end, function() end) end").exec().is_err());
+  }
+
+  #[test]
+  fn errors_are_caught_within_the_budget() {
+    let (lua, _) = sandbox();
+    assert_eq!(lua.load("return pcall(error, 'x')").eval::<bool>().unwrap(), false);
+  }
+
+  #[test]
+  fn memory_limit_holds_for_single_allocations() {
+    let (lua, budget) = sandbox();
+    assert!(lua.load("return #string.rep('x', 2^30)").eval::<i64>().is_err());
+
+    budget.store(0, Ordering::SeqCst);
+    assert_eq!(lua.load("return #string.rep('x', 1000)").eval::<i64>().unwrap(), 1000);
+  }
+}
@@ -1,11 +1,19 @@
 use std::time::{Duration, Instant};
 
+mod assets;
+mod clock;
+mod config;
 mod context;
 mod events;
+mod interpolation;
 mod lua;
 mod methatron;
 mod network;
+mod prediction;
+mod reload;
+mod stats;
 mod tracer;
+mod user;
 
 fn check_gl_error(info: &str) {
   let error = unsafe { gl::GetError() };
@@ -39,17 +47,23 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
   let pump = methatron::pump::get();
   let ev = events::get();
 
-  {
-    let ctx = ctx.clone();
-    std::thread::spawn(move || {
-      log::info!("init luajit");
-
-      if let Err(e) = lua::execute(ctx, "assets/scripts/init.lua", |_| Ok(())) {
-        log::error!("{}", e.to_string());
+  match config::load() {
+    Ok(config) => {
+      reload::enable(config.hot_reload);
+      let network = ctx.read().unwrap().network.clone();
+      if config.connect {
+        network.connect(config.server);
+      }
+      else {
+        network.set_server(config.server);
       }
-    });
+    }
+    Err(e) => log::error!("config {}", e),
   }
 
+  log::info!("init luajit");
+  lua::spawn(ctx.clone(), "assets/scripts/init.lua".to_owned(), std::collections::HashMap::new());
+
   log::info!("configure open-gl");
   unsafe {
     gl::PixelStorei(gl::UNPACK_ALIGNMENT, 1);
@@ -85,20 +99,20 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
             match state {
               ElementState::Pressed => {
                 if !is_pressed {
-                  ev.sender.send(events::Events::KeyPressed(name.clone())).unwrap();
+                  ev.publish(events::Events::KeyPressed(name.clone()));
                 }
                 ctx.write().unwrap().keys_down.insert(name);
               }
               ElementState::Released => {
                 if is_pressed {
-                  ev.sender.send(events::Events::KeyReleased(name.clone())).unwrap();
+                  ev.publish(events::Events::KeyReleased(name.clone()));
                 }
                 ctx.write().unwrap().keys_down.remove(&name);
               }
             }
           }
           WindowEvent::MouseWheel{delta: MouseScrollDelta::LineDelta(x, y), ..} => {
-            ev.sender.send(events::Events::MouseWheel(y)).unwrap();
+            ev.publish(events::Events::MouseWheel(y));
           }
           WindowEvent::MouseWheel{delta, ..} => {
             log::debug!("{:?}", delta);
@@ -127,6 +141,8 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
 
       check_gl_error("pump");
 
+      ctx.read().unwrap().network.interpolate();
+
       if let Some(scene) = &ctx.read().unwrap().scene {
         scene.read().unwrap().draw();
       }
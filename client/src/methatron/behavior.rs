@@ -1,45 +1,462 @@
-use std::sync::atomic::{AtomicU64, Ordering};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::error::Error;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::sync::{Arc, atomic::{AtomicU64, Ordering}};
+use std::time::{Duration, Instant};
+
+use crate::context;
+use crate::events::{self, Events};
+use crate::lua::{self, Sandbox};
+use crate::reload;
+use crate::methatron::{
+  error,
+  node::{Node, NodeUserData},
+};
 
 static BEHAVIOR_ID: AtomicU64 = AtomicU64::new(0u64);
 
-pub trait BehaviorExt: Send + Sync {
+/// Milliseconds between two updates of the behaviors.
+const UPDATE_INTERVAL: u64 = 30;
+
+pub trait BehaviorExt {
   fn id(&self) -> u64;
 
-  fn on_update(&self);
+  /// The behavior ends once its node is disposed.
+  fn node(&self) -> &Node;
+
+  fn on_load(&self) -> Result<(), Box<dyn Error>>;
+
+  /// `dt` is the time since the previous update in seconds.
+  fn on_update(&self, dt: f32) -> Result<(), Box<dyn Error>>;
+
+  fn on_destroy(&self) -> Result<(), Box<dyn Error>>;
+
+  /// Events the behavior subscribed to, `None` when it handles none.
+  fn events(&self) -> Option<&crossbeam_channel::Receiver<Events>>;
+
+  fn on_event(&self, event: Events) -> Result<(), Box<dyn Error>>;
 
-  fn on_load(&self);
+  /// File the behavior was loaded from.
+  fn source(&self) -> &str;
+
+  /// Binds the behavior to the current version of its source.
+  fn reload(&mut self) -> Result<(), Box<dyn Error>>;
+}
+
+/// A lua state shared by every behavior of the same trust.
+struct State {
+  lua: lua::Lua,
+  /// Instructions spent since the last call into a sandboxed state.
+  budget: Arc<AtomicU64>,
+  sandbox: Option<Sandbox>,
+  /// Metatables of the loaded scripts by path, each file is only run once.
+  scripts: RefCell<HashMap<String, mlua::RegistryKey>>,
 }
 
-pub fn from_lua(path: &str) -> LuaBehavior {
-  LuaBehavior {
-    _id: BEHAVIOR_ID.fetch_add(1, Ordering::SeqCst),
+impl State {
+  fn new(ctx: context::Context, sandbox: Option<Sandbox>) -> mlua::Result<State> {
+    let budget = Arc::new(AtomicU64::new(0));
+    let (lua, _) = lua::new_state(ctx, sandbox.as_ref(), budget.clone())?;
+
+    Ok(State {
+      lua,
+      budget,
+      sandbox,
+      scripts: RefCell::new(HashMap::new()),
+    })
+  }
+
+  /// The path `path` is cached as, scripts of a sandbox have to be inside its roots.
+  fn key(&self, path: &str) -> Result<String, Box<dyn Error>> {
+    match &self.sandbox {
+      Some(sandbox) => Ok(sandbox.check_path(path)
+        .ok_or_else(|| format!("script {} is outside of the allowed roots", path))?
+        .to_string_lossy().into_owned()),
+      None => Ok(path.to_owned()),
+    }
+  }
+
+  /// Runs `path` unless it ran already, the script returns a table with its hooks.
+  fn load(&self, path: &str) -> Result<mlua::Table, Box<dyn Error>> {
+    let path = self.key(path)?;
+
+    if let Some(key) = self.scripts.borrow().get(&path) {
+      return Ok(self.lua.registry_value(key)?);
+    }
+
+    let src = std::fs::read(&path)?;
+    self.budget.store(0, Ordering::SeqCst);
+    let hooks = match self.lua.load(&src).set_name(&path)?.eval::<mlua::Value>()? {
+      mlua::Value::Table(hooks) => hooks,
+      _ => return Err(format!("behavior {} does not return its hooks", path).into()),
+    };
+
+    let meta = self.lua.create_table()?;
+    meta.set("__index", hooks)?;
+    self.scripts.borrow_mut().insert(path, self.lua.create_registry_value(meta.clone())?);
+
+    Ok(meta)
+  }
+
+  /// Watches the loaded scripts and modules.
+  fn watch(&self, watcher: &mut reload::Watcher) -> mlua::Result<()> {
+    for path in self.scripts.borrow().keys() {
+      watcher.watch(Path::new(path));
+    }
+    reload::watch_modules(&self.lua, watcher)
+  }
+
+  /// Forgets the scripts affected by the `changed` files and returns them, which are all of them when a module changed.
+  fn forget(&self, changed: &[PathBuf]) -> mlua::Result<Vec<String>> {
+    let all = reload::forget_modules(&self.lua, changed)?;
+
+    let mut scripts = self.scripts.borrow_mut();
+    let stale: Vec<String> = scripts.keys()
+      .filter(|path| all || changed.iter().any(|file| file == Path::new(path)))
+      .cloned()
+      .collect();
+    for path in stale.iter() {
+      scripts.remove(path);
+    }
+    Ok(stale)
   }
 }
 
+/// Calls the hooks returned by a lua script, with a table holding `node` and `is_owner` as `self`.
 pub struct LuaBehavior {
   _id: u64,
+  node: Node,
+  path: String,
+  state: Rc<State>,
+  instance: mlua::RegistryKey,
+  subscription: events::Subscription,
+  events: crossbeam_channel::Receiver<Events>,
+}
+
+fn from_file(id: u64, state: Rc<State>, path: &str, node: Node, is_owner: bool) -> Result<LuaBehavior, Box<dyn Error>> {
+  let path = state.key(path)?;
+  let meta = state.load(&path)?;
+
+  let instance = state.lua.create_table()?;
+  instance.set("node", NodeUserData { node: node.clone() })?;
+  instance.set("is_owner", is_owner)?;
+  instance.set_metatable(Some(meta));
+
+  // scripts of other clients must not learn the keys of the player
+  let allowed: &'static [&'static str] = if state.sandbox.is_some() { &events::SANDBOXED_HANDLERS } else { &events::HANDLERS };
+  let (subscription, events) = events::Subscription::new(allowed);
+  lua::subscription_api(&state.lua, &instance, &subscription)?;
+  lua::subscribe_defined(&instance, &subscription)?;
+  let instance = state.lua.create_registry_value(instance)?;
+
+  Ok(LuaBehavior {
+    _id: id,
+    node,
+    path,
+    state,
+    instance,
+    subscription,
+    events,
+  })
+}
+
+impl LuaBehavior {
+  fn call<A: for<'lua> mlua::ToLuaMulti<'lua>>(&self, hook: &str, args: A) -> Result<(), Box<dyn Error>> {
+    let instance: mlua::Table = self.state.lua.registry_value(&self.instance)?;
+
+    if let Some(f) = instance.get::<_, Option<mlua::Function>>(hook)? {
+      self.state.budget.store(0, Ordering::SeqCst);
+      f.call::<_, ()>((instance, args))?;
+    }
+    Ok(())
+  }
 }
 
 impl BehaviorExt for LuaBehavior {
   fn id(&self) -> u64 { self._id }
 
-  fn on_update(&self) {
+  fn node(&self) -> &Node { &self.node }
+
+  fn on_load(&self) -> Result<(), Box<dyn Error>> {
+    self.call("on_load", ())
+  }
+
+  fn on_update(&self, dt: f32) -> Result<(), Box<dyn Error>> {
+    self.call("on_update", dt)
+  }
+
+  fn on_destroy(&self) -> Result<(), Box<dyn Error>> {
+    self.call("on_destroy", ())
+  }
+
+  fn events(&self) -> Option<&crossbeam_channel::Receiver<Events>> {
+    Some(&self.events)
+  }
+
+  fn on_event(&self, event: Events) -> Result<(), Box<dyn Error>> {
+    let lua = &self.state.lua;
+    let instance: mlua::Table = lua.registry_value(&self.instance)?;
+    // unsubscribed handlers may still have events queued
+    let handler = match instance.get::<_, Option<mlua::Function>>(event.handler())? {
+      Some(handler) => handler,
+      None => return Ok(()),
+    };
 
+    let mut args = vec![mlua::Value::Table(instance)];
+    args.extend(lua::event_args(lua, event)?);
+    self.state.budget.store(0, Ordering::SeqCst);
+    handler.call::<_, ()>(mlua::MultiValue::from_vec(args))?;
+    Ok(())
+  }
+
+  fn source(&self) -> &str {
+    &self.path
   }
 
-  fn on_load(&self) {
+  /// Keeps the instance and swaps its hooks, the value returned by `on_unload` is handed to `on_reload` of the new version.
+  fn reload(&mut self) -> Result<(), Box<dyn Error>> {
+    let lua = &self.state.lua;
+    let instance: mlua::Table = lua.registry_value(&self.instance)?;
 
+    let saved = match instance.get::<_, Option<mlua::Function>>("on_unload")? {
+      Some(on_unload) => {
+        self.state.budget.store(0, Ordering::SeqCst);
+        on_unload.call::<_, mlua::Value>(instance.clone())?
+      }
+      None => mlua::Value::Nil,
+    };
+
+    instance.set_metatable(Some(self.state.load(&self.path)?));
+    lua::subscribe_defined(&instance, &self.subscription)?;
+
+    if let Some(on_reload) = instance.get::<_, Option<mlua::Function>>("on_reload")? {
+      self.state.budget.store(0, Ordering::SeqCst);
+      on_reload.call::<_, ()>((instance, saved))?;
+    }
+    Ok(())
+  }
+}
+
+/// Delivers the pending events and updates `behavior`, returns whether it goes on.
+fn update(behavior: &dyn BehaviorExt, dt: f32) -> Result<bool, Box<dyn Error>> {
+  if behavior.node().read().unwrap().is_disposed {
+    behavior.on_destroy()?;
+    return Ok(false);
+  }
+
+  if let Some(events) = behavior.events() {
+    for event in events.try_iter() {
+      behavior.on_event(event)?;
+    }
+  }
+
+  behavior.on_update(dt)?;
+  Ok(true)
+}
+
+enum Command {
+  Attach { id: u64, node: Node, path: String, sandbox: Option<Sandbox>, owner: String, is_owner: bool },
+  Detach(u64),
+  /// Ends every behavior and unloads the scripts.
+  Clear,
+}
+
+/// Updates every behavior from one thread, which owns their lua states.
+#[derive(Clone)]
+pub struct Scheduler {
+  sender: crossbeam_channel::Sender<Command>,
+}
+
+static mut SCHEDULER: Option<Scheduler> = None;
+
+pub fn get() -> Scheduler {
+  unsafe {
+    if let Some(ref s) = SCHEDULER {
+      s.clone()
+    }
+    else {
+      let (sender, receiver) = crossbeam_channel::unbounded();
+      let ctx = context::get();
+      std::thread::spawn(move || run(ctx, receiver));
+
+      let s = Scheduler { sender };
+      SCHEDULER = Some(s.clone());
+      s
+    }
+  }
+}
+
+impl Scheduler {
+  /// Runs the script `path` on `node`, restricted by `sandbox` when the script comes from another client.
+  /// Sandboxed scripts share a state with the other scripts spawned by the same `owner`.
+  pub fn attach(&self, node: Node, path: String, sandbox: Option<Sandbox>, owner: String, is_owner: bool) -> u64 {
+    let id = BEHAVIOR_ID.fetch_add(1, Ordering::SeqCst);
+    if let Err(e) = self.sender.send(Command::Attach { id, node, path, sandbox, owner, is_owner }) {
+      log::error!("{}", e);
+    }
+    id
+  }
+
+  pub fn detach(&self, id: u64) {
+    if let Err(e) = self.sender.send(Command::Detach(id)) {
+      log::error!("{}", e);
+    }
+  }
+
+  pub fn clear(&self) {
+    if let Err(e) = self.sender.send(Command::Clear) {
+      log::error!("{}", e);
+    }
+  }
+}
+
+/// Binds the behaviors whose scripts or modules changed to the new versions.
+fn reload_changed(states: &[&Rc<State>], behaviors: &mut Vec<Box<dyn BehaviorExt>>, watcher: &mut reload::Watcher) {
+  for state in states.iter() {
+    if let Err(e) = state.watch(watcher) {
+      log::error!("behavior watch {}", e);
+    }
+  }
+
+  let changed = watcher.changed();
+  if changed.is_empty() {
+    return;
+  }
+
+  let mut stale = Vec::new();
+  for state in states.iter() {
+    match state.forget(&changed) {
+      Ok(scripts) => stale.extend(scripts),
+      Err(e) => log::error!("behavior reload {}", e),
+    }
+  }
+
+  for behavior in behaviors.iter_mut().filter(|behavior| stale.iter().any(|path| path == behavior.source())) {
+    log::info!("reload behavior {} {}", behavior.id(), behavior.source());
+    // the previous version goes on until the script is fixed
+    if let Err(e) = behavior.reload() {
+      log::error!("behavior {} {}", behavior.id(), e);
+    }
+  }
+}
+
+fn run(ctx: context::Context, commands: crossbeam_channel::Receiver<Command>) {
+  let mut trusted: Option<Rc<State>> = None;
+  // one state for the scripts of each other client, so they do not share their memory limit
+  let mut sandboxed: HashMap<String, Rc<State>> = HashMap::new();
+  let mut behaviors: Vec<Box<dyn BehaviorExt>> = Vec::new();
+  let mut last_update = Instant::now();
+  let mut watcher = reload::Watcher::new();
+
+  loop {
+    let start = Instant::now();
+
+    while let Ok(command) = commands.try_recv() {
+      match command {
+        Command::Attach { id, node, path, sandbox, owner, is_owner } => {
+          let slot = if sandbox.is_some() { sandboxed.get(&owner) } else { trusted.as_ref() };
+          let state = match slot {
+            Some(state) => state.clone(),
+            None => match State::new(ctx.clone(), sandbox) {
+              Ok(state) => {
+                let state = Rc::new(state);
+                if state.sandbox.is_some() {
+                  sandboxed.insert(owner, state.clone());
+                }
+                else {
+                  trusted = Some(state.clone());
+                }
+                state
+              }
+              Err(e) => {
+                log::error!("behavior state {}", e);
+                continue;
+              }
+            },
+          };
+
+          let behavior = from_file(id, state, &path, node, is_owner)
+            .and_then(|behavior| behavior.on_load().map(|_| behavior));
+          match behavior {
+            Ok(behavior) => behaviors.push(Box::new(behavior)),
+            Err(e) => log::error!("behavior {} {}", path, e),
+          }
+        }
+        Command::Detach(id) => {
+          if let Some(i) = behaviors.iter().position(|behavior| behavior.id() == id) {
+            let behavior = behaviors.remove(i);
+            if let Err(e) = behavior.on_destroy() {
+              log::error!("behavior {} {}", id, e);
+            }
+          }
+        }
+        Command::Clear => {
+          for behavior in behaviors.drain(..) {
+            if let Err(e) = behavior.on_destroy() {
+              log::error!("behavior {} {}", behavior.id(), e);
+            }
+          }
+          // the next scene runs its scripts in fresh states
+          trusted = None;
+          sandboxed.clear();
+        }
+      }
+    }
+
+    if watcher.is_due() {
+      let states: Vec<&Rc<State>> = trusted.iter().chain(sandboxed.values()).collect();
+      reload_changed(&states, &mut behaviors, &mut watcher);
+    }
+
+    let dt = last_update.elapsed().as_secs_f32();
+    last_update = Instant::now();
+
+    behaviors.retain(|behavior| {
+      update(behavior.as_ref(), dt).unwrap_or_else(|e| {
+        log::error!("behavior {} {}", behavior.id(), e);
+        false
+      })
+    });
+
+    // the behaviors hold their state, a client whose behaviors all ended frees its memory
+    sandboxed.retain(|_, state| Rc::strong_count(state) > 1);
+
+    for state in trusted.iter().chain(sandboxed.values()) {
+      state.budget.store(0, Ordering::SeqCst);
+      if let Err(e) = crate::network::call_spawn_callbacks(&state.lua) {
+        log::error!("behavior {}", e);
+      }
+      state.lua.expire_registry_values();
+    }
+
+    let elapsed = start.elapsed();
+    if elapsed.as_millis() < UPDATE_INTERVAL as u128 {
+      std::thread::sleep(Duration::from_millis(UPDATE_INTERVAL) - elapsed);
+    }
   }
 }
 
 pub fn load_module(lua: &mlua::Lua, ns: &mlua::Table) -> mlua::Result<()> {
   let module = lua.create_table()?;
 
-  let from_file = lua.create_function(|_,_: ()| {
-    Ok(())
+  // attaches the script to a node, returns the id to detach it again
+  let from_file = lua.create_function(|_, (path, node): (String, mlua::AnyUserData)| {
+    let node = node.borrow::<NodeUserData>()
+      .map_err(|_| error::to_lua_err("behavior needs a node"))?
+      .node.clone();
+    // scripts attached locally are trusted and belong to no other client
+    Ok(get().attach(node, path, None, String::new(), true))
   })?;
   module.set("from_file", from_file)?;
 
+  let detach = lua.create_function(|_, id: u64| {
+    get().detach(id);
+    Ok(())
+  })?;
+  module.set("detach", detach)?;
+
   ns.set("behavior", module)?;
   Ok(())
-}
\ No newline at end of file
+}
@@ -2,7 +2,10 @@ use std::sync::{Arc, Mutex};
 use std::rc::Rc;
 use std::cell::RefCell;
 
-use crate::methatron::math::vector::{self, Vector, VectorUserData};
+use crate::methatron::math::{
+  quaternion::{self, Quaternion},
+  vector::{self, Vector, VectorUserData},
+};
 
 pub type Matrix = Arc<Mutex<[f32; 16]>>;
 
@@ -375,6 +378,55 @@ pub fn rotation(m: &[f32; 16]) -> [f32; 4] {
   return [qx, qy, qz, qw];
 }
 
+/// Splits an affine transform into position, rotation and scale.
+pub fn decompose(m: &[f32; 16]) -> (Vector, Quaternion, Vector) {
+  let position = [m[12], m[13], m[14]];
+  let scale = [
+    vector::magnitude(&[m[0], m[1], m[2]]),
+    vector::magnitude(&[m[4], m[5], m[6]]),
+    vector::magnitude(&[m[8], m[9], m[10]]),
+  ];
+
+  let mut r = *m;
+  for col in 0..3 {
+    for row in 0..3 {
+      r[col * 4 + row] /= scale[col];
+    }
+  }
+
+  (position, rotation(&r), scale)
+}
+
+pub fn compose(position: &Vector, rotation: &Quaternion, scale: &Vector) -> [f32; 16] {
+  let mut m = [0.0; 16];
+  identity(&mut m);
+  quaternion::to_matrix(rotation, &mut m);
+
+  for col in 0..3 {
+    for row in 0..3 {
+      m[col * 4 + row] *= scale[col];
+    }
+  }
+  m[12] = position[0];
+  m[13] = position[1];
+  m[14] = position[2];
+
+  m
+}
+
+/// Blends two transforms by lerping position and scale and slerping the rotation.
+/// A `percent` above one extrapolates past `b`.
+pub fn interpolate(a: &[f32; 16], b: &[f32; 16], percent: f32) -> [f32; 16] {
+  let (mut position, rot_a, mut scale) = decompose(a);
+  let (position_b, rot_b, scale_b) = decompose(b);
+
+  vector::lerp(&mut position, &position_b, percent);
+  vector::lerp(&mut scale, &scale_b, percent);
+  let rotation = quaternion::slerp(&rot_a, &rot_b, percent);
+
+  compose(&position, &rotation, &scale)
+}
+
 pub fn inverse(m: &[f32; 16], res: &mut [f32; 16]) {
   let d = determinant(m);
 
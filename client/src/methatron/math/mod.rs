@@ -1,4 +1,5 @@
 pub mod matrix;
+pub mod quaternion;
 pub mod vector;
 
 pub fn load_module(lua: &mlua::Lua, ns: &mlua::Table) -> Result<(), mlua::Error> {
@@ -0,0 +1,58 @@
+pub type Quaternion = [f32; 4];
+
+pub fn dot(a: &Quaternion, b: &Quaternion) -> f32 {
+  return a[0] * b[0] + a[1] * b[1] + a[2] * b[2] + a[3] * b[3];
+}
+
+pub fn normalize(q: &mut Quaternion) {
+  let sum = dot(q, q).sqrt();
+
+  q[0] /= sum;
+  q[1] /= sum;
+  q[2] /= sum;
+  q[3] /= sum;
+}
+
+pub fn slerp(a: &Quaternion, b: &Quaternion, percent: f32) -> Quaternion {
+  let mut end = *b;
+  let mut d = dot(a, b);
+
+  // take the short way around
+  if d < 0.0 {
+    d = -d;
+    end = [-b[0], -b[1], -b[2], -b[3]];
+  }
+
+  let (wa, wb) = if d > 0.9995 {
+    (1.0 - percent, percent)
+  } else {
+    let theta = d.acos();
+    let stheta = theta.sin();
+    (((1.0 - percent) * theta).sin() / stheta, (percent * theta).sin() / stheta)
+  };
+
+  let mut q = [
+    wa * a[0] + wb * end[0],
+    wa * a[1] + wb * end[1],
+    wa * a[2] + wb * end[2],
+    wa * a[3] + wb * end[3],
+  ];
+  normalize(&mut q);
+
+  q
+}
+
+/// Writes the rotation of `q` into the upper 3x3 part of `m`.
+pub fn to_matrix(q: &Quaternion, m: &mut [f32; 16]) {
+  let (x, y, z, w) = (q[0], q[1], q[2], q[3]);
+
+  m[0] = 1.0 - 2.0 * (y * y + z * z);
+  m[1] = 2.0 * (x * y + w * z);
+  m[2] = 2.0 * (x * z - w * y);
+  m[4] = 2.0 * (x * y - w * z);
+  m[5] = 1.0 - 2.0 * (x * x + z * z);
+  m[6] = 2.0 * (y * z + w * x);
+  m[8] = 2.0 * (x * z + w * y);
+  m[9] = 2.0 * (y * z - w * x);
+  m[10] = 1.0 - 2.0 * (x * x + y * y);
+}
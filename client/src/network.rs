@@ -1,182 +1,636 @@
 use std::collections::HashMap;
-use std::net::TcpStream;
-use std::sync::{Arc, Mutex, RwLock, Condvar, atomic::{AtomicBool, Ordering}};
-use std::time::Duration;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock, Condvar, atomic::{AtomicBool, AtomicU64, Ordering}};
+use std::time::{Duration, Instant};
 
-use shadow_of_truth_common as common;
+use shadow_of_truth_common::{self as common, transport::{self, SyncStream}};
 use crate::{
+  assets::Assets,
+  clock::Clock,
+  config,
   events,
-  methatron::node::{
-    Node,
-    NodeUserData,
-  }
+  interpolation::InterpolationBuffer,
+  lua::Sandbox,
+  prediction::Prediction,
+  stats::{Counted, Stats},
+  methatron::{
+    error,
+    material::{ImplMaterial, MaterialUserData},
+    math::matrix::MatrixUserData,
+    node::{
+      Node,
+      NodeUserData,
+    },
+  },
+  user::User,
 };
 
+/// Seconds between two attempts to connect, the network gives up after the last one.
+const RECONNECT_DELAYS: [u64; 6] = [1, 2, 4, 8, 16, 30];
+
+/// Milliseconds a spawn waits for the confirmation of the server, unless it asks for another timeout.
+const SPAWN_TIMEOUT: u64 = 5000;
+
+/// Transform ticks between two pings, which is about a second.
+const PING_INTERVAL: u64 = 20;
+
+/// Milliseconds remote nodes keep moving along their last motion once updates stop arriving.
+/// Independent of the interpolation delay, which only hides the jitter of updates that do arrive.
+const MAX_EXTRAPOLATION: u64 = 250;
+
+/// Registry key of the `spawn_async` callbacks of a lua state.
+const SPAWN_CALLBACKS: &str = "network_spawn_callbacks";
+
+/// Filled with the spawned node once the server confirmed it, or the reason it failed.
+type SpawnResult = Arc<(Mutex<Option<Result<Node, String>>>, Condvar)>;
+
+struct SpawnOptions {
+  behavior: Option<String>,
+  parent: Option<String>,
+  transform: Option<[f32; 16]>,
+  material: Option<common::MaterialInfo>,
+  timeout: Duration,
+}
+
+impl Default for SpawnOptions {
+  fn default() -> SpawnOptions {
+    SpawnOptions {
+      behavior: None,
+      parent: None,
+      transform: None,
+      material: None,
+      timeout: Duration::from_millis(SPAWN_TIMEOUT),
+    }
+  }
+}
+
+impl SpawnOptions {
+  fn from_table(options: &mlua::Table) -> mlua::Result<SpawnOptions> {
+    let transform = match options.get::<_, Option<mlua::AnyUserData>>("transform")? {
+      Some(ud) => {
+        let m = ud.borrow::<MatrixUserData>()?;
+        let t = *m.matrix.lock().unwrap();
+        Some(t)
+      }
+      None => None,
+    };
+    let material = match options.get::<_, Option<mlua::AnyUserData>>("material")? {
+      Some(ud) => {
+        let m = ud.borrow::<MaterialUserData>()?;
+        let info = material_info(&m.0.read().unwrap());
+        Some(info)
+      }
+      None => None,
+    };
+    let timeout = options.get::<_, Option<u64>>("timeout")?.unwrap_or(SPAWN_TIMEOUT);
+
+    Ok(SpawnOptions {
+      behavior: options.get("behavior")?,
+      parent: options.get("parent")?,
+      transform: transform,
+      material: material,
+      timeout: Duration::from_millis(timeout),
+    })
+  }
+}
+
+/// A spawn sent to the server, which is neither confirmed nor rejected yet.
 #[derive(Clone)]
-pub struct Network {
+pub struct PendingSpawn {
+  network: Network,
   id: String,
-  writer: Arc<Mutex<Option<TcpStream>>>,
-  owned: Arc<RwLock<HashMap<String, (String, Node)>>>,
+  scene: String,
+  result: SpawnResult,
+  deadline: Instant,
+}
+
+impl PendingSpawn {
+  /// Stops waiting with the error `reason`, returns false when the reply arrived in the meantime.
+  fn cancel(&self, reason: &str) -> bool {
+    if !self.network.cancel_spawn(&self.id, &self.scene) {
+      return false;
+    }
+    *self.result.0.lock().unwrap() = Some(Err(format!("spawn of {} {}", self.id, reason)));
+    self.result.1.notify_all();
+    true
+  }
+
+  /// The result without blocking, `None` while the spawn is still pending.
+  fn poll(&self) -> Option<Result<Node, String>> {
+    if let Some(result) = self.result.0.lock().unwrap().as_ref() {
+      return Some(result.clone());
+    }
+    if Instant::now() >= self.deadline && self.cancel("timed out") {
+      return self.poll();
+    }
+    None
+  }
+
+  /// Blocks until the spawn is confirmed, rejected or timed out.
+  fn wait(&self) -> Result<Node, String> {
+    let (lock, cvar) = &*self.result;
+    {
+      let mut result = lock.lock().unwrap();
+      loop {
+        if let Some(result) = result.as_ref() {
+          return result.clone();
+        }
+        let now = Instant::now();
+        if now >= self.deadline {
+          break;
+        }
+        result = cvar.wait_timeout(result, self.deadline - now).unwrap().0;
+      }
+    }
+
+    // the reader locks the waiters before the result, so the result must not be locked while cancelling
+    self.cancel("timed out");
+
+    let mut result = lock.lock().unwrap();
+    while result.is_none() {
+      result = cvar.wait(result).unwrap();
+    }
+    result.clone().unwrap()
+  }
+}
+
+impl mlua::UserData for PendingSpawn {
+  fn add_methods<'lua, M: mlua::UserDataMethods<'lua, Self>>(methods: &mut M) {
+    methods.add_method("id", |_, this, (): ()| {
+      Ok(this.id.clone())
+    });
+
+    // for coroutines: `while not pending:done() do coroutine.yield() end`
+    methods.add_method("done", |_, this, (): ()| {
+      Ok(this.poll().is_some())
+    });
+
+    // the node or nil and the reason the spawn failed, both nil while pending
+    methods.add_method("result", |_, this, (): ()| {
+      match this.poll() {
+        Some(Ok(node)) => Ok((Some(NodeUserData { node: node }), None)),
+        Some(Err(e)) => Ok((None, Some(e))),
+        None => Ok((None, None)),
+      }
+    });
+
+    methods.add_method("wait", |_, this, (): ()| {
+      match this.wait() {
+        Ok(node) => Ok(NodeUserData { node: node }),
+        Err(e) => Err(error::to_lua_err(&e)),
+      }
+    });
+
+    methods.add_method("cancel", |_, this, (): ()| {
+      Ok(this.cancel("was cancelled"))
+    });
+  }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum ConnectionState {
+  Disconnected,
+  Connecting,
+  Connected,
+  /// The connection was lost and is established again.
+  Reconnecting,
+}
+
+impl ConnectionState {
+  pub fn name(&self) -> &'static str {
+    match self {
+      ConnectionState::Disconnected => "disconnected",
+      ConnectionState::Connecting => "connecting",
+      ConnectionState::Connected => "connected",
+      ConnectionState::Reconnecting => "reconnecting",
+    }
+  }
+}
+
+/// The room in lockstep this client is in.
+struct Lockstep {
+  scene: String,
+  /// The next tick expected from the server.
+  tick: u64,
+  config: common::LockstepConfig,
+}
+
+#[derive(Clone)]
+pub struct Network {
+  user: Arc<User>,
+  writer: Arc<Mutex<Option<Box<dyn SyncStream>>>>,
+  /// Codec of the outgoing messages, only changed while holding the writer lock.
+  write_codec: Arc<RwLock<common::Codec>>,
+  /// Codec requested after every connect.
+  codec: Arc<RwLock<common::Codec>>,
+  /// Frames above this size are compressed by both sides, requested after every connect.
+  compression: Arc<RwLock<Option<u32>>>,
+  /// Scene, node and unacknowledged inputs of every node spawned by this client.
+  owned: Arc<RwLock<HashMap<String, (String, Node, Prediction)>>>,
   synced_nodes: Arc<RwLock<HashMap<String, Node>>>,
-  waiting: Arc<RwLock<HashMap<String, Arc<(Mutex<Option<Node>>, Condvar)>>>>,
+  buffers: Arc<RwLock<HashMap<String, InterpolationBuffer>>>,
+  assets: Arc<Mutex<Assets>>,
+  /// Milliseconds remote nodes are rendered behind the latest received update.
+  interpolation_delay: Arc<AtomicU64>,
+  waiting: Arc<RwLock<HashMap<String, SpawnResult>>>,
+  /// Maps the request id of a pending spawn to the spawned network id.
+  spawn_requests: Arc<RwLock<HashMap<u64, String>>>,
+  next_request: Arc<AtomicU64>,
+  /// Address of the current server, or the one connected to next.
+  server: Arc<RwLock<String>>,
+  /// Counts the connects and disconnects, the threads of an older connection stop once it changed.
+  connection: Arc<AtomicU64>,
+  state: Arc<RwLock<ConnectionState>>,
+  /// Room and password joined last, joined again after reconnecting.
+  room: Arc<RwLock<Option<(String, Option<String>)>>>,
+  /// Maps the request id of a pending join to its room and password.
+  join_requests: Arc<RwLock<HashMap<u64, (String, Option<String>)>>>,
+  lockstep: Arc<RwLock<Option<Lockstep>>>,
+  stats: Arc<Stats>,
+  clock: Arc<Mutex<Clock>>,
+  started: Instant,
   running: Arc<AtomicBool>,
 }
 
 impl Network {
-  pub fn establish_connection(&self) {
+  /// Connects after `delay`, retrying with growing delays until it succeeds or the connection is replaced.
+  pub fn establish_connection(&self, delay: Duration) {
     let mut net = self.clone();
+    let connection = self.connection.load(Ordering::SeqCst);
 
     std::thread::spawn(move || {
-      let mut count = 0;
-
-      while let Err(e) = net.try_connect() {
-        log::warn!("{}", e.to_string());
+      let mut delays = RECONNECT_DELAYS.iter();
+      let mut delay = delay;
 
-        count += 1;
+      loop {
+        std::thread::sleep(delay);
 
-        if count > 3 {
-          log::error!("could not establish connection");
+        if !net.is_current(connection) {
           return;
         }
 
-        std::thread::sleep(Duration::from_secs(count * 10));
+        match net.try_connect(connection) {
+          Ok(()) => return,
+          Err(e) => {
+            log::warn!("{}", e.to_string());
+
+            match delays.next() {
+              Some(seconds) => delay = Duration::from_secs(*seconds),
+              None => {
+                log::error!("could not establish connection");
+                net.set_state(ConnectionState::Disconnected);
+                return;
+              }
+            }
+          }
+        }
       }
     });
   }
 
-  pub fn try_connect(&mut self) -> Result<(), Box<dyn std::error::Error>> {
-    let mut reader = TcpStream::connect("127.0.0.1:3000")?;
+  pub fn state(&self) -> ConnectionState {
+    *self.state.read().unwrap()
+  }
+
+  fn set_state(&self, state: ConnectionState) {
+    {
+      let mut current = self.state.write().unwrap();
+      if *current == state {
+        return;
+      }
+      *current = state;
+    }
+    log::info!("connection {}", state.name());
+    self.emit(events::Events::ConnectionState(state.name().to_owned()));
+  }
+
+  /// Called by the reader of `connection` when the server closed it or it broke.
+  fn connection_lost(&self, connection: u64, delay: Duration) {
+    // a disconnect on purpose already moved on to the next connection
+    if !self.is_current(connection)
+      || self.connection.compare_exchange(connection, connection + 1, Ordering::SeqCst, Ordering::SeqCst).is_err() {
+      return;
+    }
+
+    log::warn!("connection lost, reconnect in {:?}", delay);
+    self.writer.lock().unwrap().take();
+    self.clear();
+    self.emit(events::Events::Disconnected);
+    self.set_state(ConnectionState::Reconnecting);
+    self.establish_connection(delay);
+  }
+
+  /// Milliseconds since the network was created, the clock of pings.
+  fn millis(&self) -> u64 {
+    self.started.elapsed().as_millis() as u64
+  }
+
+  /// Estimated wall clock of the server in milliseconds since the unix epoch, `None` until the first pong.
+  pub fn server_time(&self) -> Option<u64> {
+    let offset = self.clock.lock().unwrap().offset()?;
+    Some((self.millis() as i64 + offset).max(0) as u64)
+  }
+
+  fn is_current(&self, connection: u64) -> bool {
+    self.running.load(Ordering::SeqCst) && self.connection.load(Ordering::SeqCst) == connection
+  }
+
+  pub fn try_connect(&mut self, connection: u64) -> Result<(), Box<dyn std::error::Error>> {
+    let server = self.server.read().unwrap().clone();
+    let mut reader: Box<dyn SyncStream> = Box::new(Counted::new(transport::connect(&server)?, self.stats.clone()));
     {
       let mut w = self.writer.lock().unwrap();
-      *w = Some(reader.try_clone()?);
+      if !self.is_current(connection) {
+        // disconnected or switched to another server while connecting
+        reader.shutdown_stream()?;
+        return Ok(());
+      }
+      *w = Some(reader.try_clone_stream()?);
+      *self.write_codec.write().unwrap() = common::Codec::default();
     }
+    log::info!("connected to {}", server);
 
-    self.send(common::Message::Login{id: self.id.clone()});
+    let codec = *self.codec.read().unwrap();
+    if codec != common::Codec::default() {
+      self.set_codec(codec);
+    }
 
-    let ep = events::get();
-    ep.sender.send(events::Events::Connected)?;
+    let threshold = *self.compression.read().unwrap();
+    if threshold.is_some() {
+      self.set_compression(threshold);
+    }
 
+    self.send(common::Message::Login{public_key: self.user.public_key()});
 
     let network = self.clone();
     std::thread::spawn(move || {
       let ctx = crate::context::get();
+      let mut codec = common::Codec::default();
+      let mut reconnect_after = Duration::from_secs(RECONNECT_DELAYS[0]);
 
-      while network.running.load(Ordering::SeqCst) {
-        match common::read(&mut reader) {
+      while network.is_current(connection) {
+        match common::read(&mut reader, codec) {
           Ok(Some(msg)) => {
-            match &msg {
-              &common::Message::TransformUpdate{..} => {}
-              m @ _ => {log::debug!("{:?}", m)}
-            }
+            let messages = match msg {
+              common::Message::Batch{messages} => messages,
+              m => vec![m],
+            };
+
+            for msg in messages {
+              network.stats.received.add_message();
 
-            match msg {
-              common::Message::Spawn{id, scene, drawable, behavior} => {
-                let c = ctx.read().unwrap();
-
-                if let Some(ref sc) = c.scene {
-                  let sc = sc.read().unwrap();
-                  let node = crate::methatron::node::new();
-                  let drawble = sc.drawables.get(&drawable).unwrap().clone();
-                  {
-                    let mut n = node.write().unwrap();
-                    n.network_id = id.clone();
-                    n.set_drawable(drawble);
+              match &msg {
+                &common::Message::TransformUpdate{..} | &common::Message::Pong{..} => {}
+                m @ _ => {log::debug!("{}", m.kind())}
+              }
+
+              match msg {
+                common::Message::Spawn{id, scene, drawable, behavior, parent, transform, material, owner} => {
+                  let c = ctx.read().unwrap();
+
+                  if let Some(ref sc) = c.scene {
+                    let sc = sc.read().unwrap();
+                    let node = crate::methatron::node::new();
+                    {
+                      let mut n = node.write().unwrap();
+                      n.network_id = id.clone();
+                      match sc.drawables.get(&drawable) {
+                        Some(d) => n.set_drawable(d.clone()),
+                        None => log::warn!("spawn {} with unknown drawable {}", id, drawable),
+                      }
+                      if let Some(t) = transform {
+                        *n.transform.lock().unwrap() = t;
+                      }
+                      if let Some(ref m) = material {
+                        apply_material(&mut n.material.write().unwrap(), m);
+                      }
+                    }
+                    {
+                      let mut nodes = network.synced_nodes.write().unwrap();
+                      nodes.insert(id.clone(), node.clone());
+                    }
+                    {
+                      let mut waiters = network.waiting.write().unwrap();
+                      if let Some(pair) = waiters.remove(&id) {
+                        let mut owned = network.owned.write().unwrap();
+                        owned.insert(id.clone(), (scene, node.clone(), Prediction::new()));
+                        network.spawn_requests.write().unwrap().retain(|_, spawn| spawn != &id);
+                        let mut opt_node = pair.0.lock().unwrap();
+                        *opt_node = Some(Ok(node.clone()));
+                        pair.1.notify_one();
+                      }
+                    }
+                    // the server sets the owner, a pending request with the same id proves nothing
+                    let is_owner = owner.as_deref() == Some(network.user.id());
+
+                    let parent_node = parent.and_then(|p| network.synced_nodes.read().unwrap().get(&p).cloned());
+                    match parent_node {
+                      Some(parent_node) => parent_node.write().unwrap().add_child(node.clone()),
+                      None => sc.root.write().unwrap().add_child(node.clone()),
+                    }
+
+                    if let Some(bhv) = behavior {
+                      let (bhv, cached) = {
+                        let assets = network.assets.lock().unwrap();
+                        let bhv = match assets.path(&bhv) {
+                          Some(file) => file.to_string_lossy().into_owned(),
+                          None => bhv,
+                        };
+                        let cached = match (Path::new(&bhv).canonicalize(), assets.cache_dir().canonicalize()) {
+                          (Ok(file), Ok(cache)) => file.starts_with(cache),
+                          _ => false,
+                        };
+                        (bhv, cached)
+                      };
+                      // scripts of other clients may come from anyone and downloaded ones from the server,
+                      // only the own local ones are trusted
+                      let sandbox = if is_owner && !cached { None } else { Some(network.behavior_sandbox()) };
+                      // the behavior ends with the node
+                      crate::methatron::behavior::get().attach(node.clone(), bhv, sandbox, owner.unwrap_or_default(), is_owner);
+                    }
                   }
-                  {
-                    let mut nodes = network.synced_nodes.write().unwrap();
-                    nodes.insert(id.clone(), node.clone());
+                }
+                common::Message::Manifest{scene, assets} => {
+                  network.joined(&scene);
+                  let missing = network.assets.lock().unwrap().check(scene.clone(), assets);
+                  if missing.is_empty() {
+                    network.emit(events::Events::AssetsReady(scene));
+                  }
+                  for path in missing {
+                    log::info!("download asset {}", path);
+                    network.send(common::Message::FetchAsset{path});
+                  }
+                }
+                common::Message::LockstepState{scene, tick, config} => {
+                  log::info!("{} runs in lockstep every {} ms", scene, config.tick_ms);
+                  *network.lockstep.write().unwrap() = Some(Lockstep { scene: scene.clone(), tick, config });
+                  network.emit(events::Events::Lockstep{scene, tick, config});
+                }
+                common::Message::Tick{scene, tick, inputs} => {
+                  if let Some(lockstep) = network.lockstep.write().unwrap().as_mut() {
+                    if lockstep.scene == scene {
+                      lockstep.tick = tick + 1;
+                    }
                   }
-                  let is_owner = {
-                    let mut waiters = network.waiting.write().unwrap();
-                    if let Some(pair) = waiters.remove(&id) {
-                      let mut owned = network.owned.write().unwrap();
-                      owned.insert(id.clone(), (scene, node.clone()));
-                      let mut opt_node = pair.0.lock().unwrap();
-                      *opt_node = Some(node.clone());
-                      pair.1.notify_one();
-
-                      true
+                  network.emit(events::Events::Tick{scene, tick, inputs});
+                }
+                common::Message::Desync{scene, tick, checksums} => {
+                  log::warn!("{} desynchronized at tick {}: {:?}", scene, tick, checksums);
+                  network.emit(events::Events::Desync{scene, tick, checksums});
+                }
+                common::Message::AssetChunk{path, offset, total, data} => {
+                  let mut assets = network.assets.lock().unwrap();
+                  match assets.receive(&path, offset, total, &data) {
+                    Ok(true) if assets.is_complete() => {
+                      network.emit(events::Events::AssetsReady(assets.scene().to_owned()));
                     }
-                    else {
-                      false
+                    Ok(_) => {}
+                    Err(e) => {
+                      log::error!("{}", e);
+                      network.emit(events::Events::Error{code: "AssetDownload".to_owned(), reason: e, in_reply_to: None});
                     }
-                  };
-
-                  sc.root.write().unwrap().add_child(node.clone());
-
-                  if let Some(bhv) = behavior {
-                    let ctx = ctx.clone();
-                    let node = node.clone();
-                    std::thread::spawn(move || {
-                      if let Err(e) = crate::lua::execute(ctx, &bhv, move |globals| {
-                        globals.set("node", crate::methatron::node::NodeUserData{ node: node.clone() })?;
-                        globals.set("is_owner", is_owner)?;
-                        Ok(())
-                      }) {
-                        log::error!("{}", e);
-                      }
-                    });
                   }
                 }
-              }
-              common::Message::Destroy{id, scene} => {
-                let mut owned = network.owned.write().unwrap();
-                owned.remove(&id);
+                common::Message::CodecChanged{codec: changed} => {
+                  log::info!("server switched to {:?}", changed);
+                  codec = changed;
+                }
+                common::Message::Pong{time, server_time} => {
+                  let now = network.millis();
+                  network.stats.set_rtt(Some(now.saturating_sub(time)));
+                  network.clock.lock().unwrap().add(time, now, server_time);
+                }
+                common::Message::CompressionChanged{threshold} => {
+                  log::info!("server compresses frames above {:?} bytes", threshold);
+                }
+                common::Message::Challenge{nonce} => {
+                  match network.user.sign(&nonce) {
+                    Ok(signature) => { network.send(common::Message::Authenticate{signature}); }
+                    Err(e) => log::error!("sign challenge {}", e),
+                  }
+                }
+                common::Message::Welcome{id} => {
+                  log::info!("logged in as {}", id);
+                  let reconnected = network.state() == ConnectionState::Reconnecting;
+                  network.set_state(ConnectionState::Connected);
+
+                  let room = network.room.read().unwrap().clone();
+                  if let Some((scene, password)) = room {
+                    log::info!("join {} again", scene);
+                    network.join(scene, password);
+                  }
 
-                let mut synced_nodes = network.synced_nodes.write().unwrap(); 
-                if let Some(node) = synced_nodes.remove(&id) {
-                  node.write().unwrap().dispose();
+                  network.emit(events::Events::Connected{reconnected});
                 }
-              }
-              common::Message::TransformUpdate{id, scene, t} => {
-                let is_owned = {
-                  let owned = network.owned.read().unwrap();
-                  owned.contains_key(&id)
-                };
-                if !is_owned {
-                  let nodes = network.synced_nodes.read().unwrap();
-                  if let Some(node) = nodes.get(&id) {
-                    // log::debug!("sync {} {:?}", id, t);
+                common::Message::Error{code, reason, in_reply_to} => {
+                  log::warn!("server rejected {:?}: {:?} {}", in_reply_to, code, reason);
+
+                  if let Some(request) = in_reply_to {
+                    network.join_requests.write().unwrap().remove(&request);
+                  }
+
+                  let spawn = in_reply_to.and_then(|request| network.spawn_requests.write().unwrap().remove(&request));
+                  let waiter = spawn.and_then(|id| network.waiting.write().unwrap().remove(&id));
+
+                  if let Some(pair) = waiter {
+                    let mut opt_node = pair.0.lock().unwrap();
+                    *opt_node = Some(Err(format!("{:?}: {}", code, reason)));
+                    pair.1.notify_one();
+                  }
+                  else {
+                    network.emit(events::Events::Error{code: format!("{:?}", code), reason, in_reply_to});
+                  }
+                }
+                common::Message::Rooms{rooms} => {
+                  network.emit(events::Events::Rooms(rooms));
+                }
+                common::Message::Offenders{offenders} => {
+                  network.emit(events::Events::Offenders(offenders));
+                }
+                common::Message::ServerShutdown{reason, reconnect_after: after} => {
+                  log::warn!("server shutdown: {}", reason);
+                  if let Some(seconds) = after {
+                    reconnect_after = Duration::from_secs(seconds);
+                  }
+                  network.emit(events::Events::ServerShutdown{reason, reconnect_after: after});
+                }
+                common::Message::Destroy{id, ..} => {
+                  network.destroy_node(&id);
+                }
+                common::Message::TransformUpdate{id, t, time, seq, ..} => {
+                  let mut owned = network.owned.write().unwrap();
+                  if let Some((_, node, prediction)) = owned.get_mut(&id) {
                     let node = node.read().unwrap();
-                    *node.transform.lock().unwrap() = t;
+                    let mut transform = node.transform.lock().unwrap();
+                    if let Some(corrected) = prediction.reconcile(seq, &t, &transform) {
+                      log::debug!("server corrected {} at {}", id, seq);
+                      *transform = corrected;
+                    }
+                  }
+                  else {
+                    let mut buffers = network.buffers.write().unwrap();
+                    buffers.entry(id).or_insert_with(InterpolationBuffer::new).push(time, t);
                   }
                 }
+                _ => {}
               }
-              _ => {}
             }
           }
           Ok(None) => { break }
           Err(e) => {
+            if let Some(common::ReadError::Decode(e)) = e.downcast_ref::<common::ReadError>() {
+              log::warn!("skip message {}", e);
+              continue;
+            }
             log::error!("read {}", e.to_string());
             break;
           }
         }
       }
+
+      network.connection_lost(connection, reconnect_after);
     });
 
     let network = self.clone();
     std::thread::spawn(move || {
-      while network.running.load(Ordering::SeqCst) {
+      let mut tick: u64 = 0;
+
+      while network.is_current(connection) {
         {
           let mut writer = network.writer.lock().unwrap();
           if let Some(ref mut writer) = *writer {
+            let codec = *network.write_codec.read().unwrap();
+            let compression = *network.compression.read().unwrap();
 
-            let owned = network.owned.read().unwrap();
-            for (scene, node) in owned.values() {
+            if tick % PING_INTERVAL == 0 {
+              let ping = common::Message::Ping { time: network.millis() };
+              match common::write(writer, codec, compression, ping) {
+                Ok(_) => network.stats.sent.add_message(),
+                Err(e) => log::error!("ping {}", e),
+              }
+            }
+
+            let mut owned = network.owned.write().unwrap();
+            for (scene, node, prediction) in owned.values_mut() {
               let node = node.read().unwrap();
+              let t = node.transform.lock().unwrap().clone();
               let msg = common::Message::TransformUpdate {
                 id: node.network_id.clone(),
-                t: node.transform.lock().unwrap().clone(),
+                seq: prediction.record(&t),
+                t: t,
                 scene: scene.clone(),
+                time: 0,
               };
 
-              if let Err(e) = common::write(writer, msg) {
-                log::error!("transform update {}", e);
+              match common::write(writer, codec, compression, msg) {
+                Ok(_) => network.stats.sent.add_message(),
+                Err(e) => log::error!("transform update {}", e),
               }
             }
           }
         }
 
+        tick += 1;
         std::thread::sleep(std::time::Duration::from_millis(50));
       }
     });
@@ -184,81 +638,523 @@ impl Network {
     Ok(())
   }
 
-  pub fn send(&self, msg: common::Message) {
+  /// Moves every remote node to its interpolated transform, called once per frame.
+  pub fn interpolate(&self) {
+    let delay = Duration::from_millis(self.interpolation_delay.load(Ordering::SeqCst));
+    let buffers = self.buffers.read().unwrap();
+    let nodes = self.synced_nodes.read().unwrap();
+
+    for (id, buffer) in buffers.iter() {
+      if let (Some(node), Some(t)) = (nodes.get(id), buffer.sample(delay, Duration::from_millis(MAX_EXTRAPOLATION))) {
+        let node = node.read().unwrap();
+        *node.transform.lock().unwrap() = t;
+      }
+    }
+  }
+
+  /// Disposes the synced node `id` and every synced node attached below it.
+  fn destroy_node(&self, id: &str) {
+    self.owned.write().unwrap().remove(id);
+    self.buffers.write().unwrap().remove(id);
+
+    let node = self.synced_nodes.write().unwrap().remove(id);
+    if let Some(node) = node {
+      let children: Vec<String> = node.read().unwrap().children.values()
+        .map(|child| child.read().unwrap().network_id.clone())
+        .filter(|network_id| !network_id.is_empty())
+        .collect();
+
+      for child in children {
+        self.destroy_node(&child);
+      }
+
+      node.write().unwrap().dispose();
+    }
+  }
+
+  /// Sends a spawn without waiting for the confirmation of the server.
+  fn request_spawn(&self, scene: String, drawable: String, options: SpawnOptions) -> PendingSpawn {
+    let id = nanoid::nanoid!(32);
+    let result: SpawnResult = Arc::new((Mutex::new(None), Condvar::new()));
+    self.waiting.write().unwrap().insert(id.clone(), result.clone());
+
+    let request = self.next_request();
+    self.spawn_requests.write().unwrap().insert(request, id.clone());
+
+    self.send_request(request, common::Message::Spawn {
+      id: id.clone(),
+      scene: scene.clone(),
+      drawable: drawable,
+      behavior: options.behavior,
+      parent: options.parent,
+      transform: options.transform,
+      material: options.material,
+      owner: None,
+    });
+
+    PendingSpawn {
+      network: self.clone(),
+      id: id,
+      scene: scene,
+      result: result,
+      deadline: Instant::now() + options.timeout,
+    }
+  }
+
+  /// Spawns a node and blocks until the server confirmed it or the timeout passed.
+  fn spawn(&self, scene: String, drawable: String, options: SpawnOptions) -> mlua::Result<NodeUserData> {
+    match self.request_spawn(scene, drawable, options).wait() {
+      Ok(node) => Ok(NodeUserData { node: node }),
+      Err(e) => Err(error::to_lua_err(&e)),
+    }
+  }
+
+  /// Stops waiting for the spawn `id`, returns false when its reply arrived already.
+  /// The server may still create the entity, so it is destroyed right away.
+  fn cancel_spawn(&self, id: &str, scene: &str) -> bool {
+    if self.waiting.write().unwrap().remove(id).is_none() {
+      return false;
+    }
+    self.spawn_requests.write().unwrap().retain(|_, spawn| spawn != id);
+    self.send(common::Message::Destroy { id: id.to_owned(), scene: scene.to_owned() });
+    true
+  }
+
+  /// Limits for behaviors of nodes spawned by other clients, each client gets a state and its memory.
+  fn behavior_sandbox(&self) -> Sandbox {
+    let cache = self.assets.lock().unwrap().cache_dir().join("scripts");
+
+    Sandbox {
+      roots: vec![PathBuf::from("assets/scripts"), cache],
+      instructions: 1_000_000,
+      memory: 16 * 1024 * 1024,
+    }
+  }
+
+  fn emit(&self, event: events::Events) {
+    events::get().publish(event);
+  }
+
+  fn next_request(&self) -> u64 {
+    self.next_request.fetch_add(1, Ordering::SeqCst)
+  }
+
+  /// Sends `msg` as a new request and returns its id, which the server refers to when rejecting it.
+  pub fn send(&self, msg: common::Message) -> u64 {
+    let request = self.next_request();
+    self.send_request(request, msg);
+    request
+  }
+
+  fn send_request(&self, request: u64, msg: common::Message) {
     let mut writer = self.writer.lock().unwrap();
     if let Some(ref mut writer) = *writer {
-      if let Err(e) = common::write(writer, msg) {
+      let msg = common::Message::Request { id: request, message: Box::new(msg) };
+      let codec = *self.write_codec.read().unwrap();
+      let compression = *self.compression.read().unwrap();
+      match common::write(writer, codec, compression, msg) {
+        Ok(_) => self.stats.sent.add_message(),
+        Err(e) => log::error!("write {}", e.to_string()),
+      }
+    }
+  }
+
+  /// Asks the server to switch to `codec`, the messages sent after the request are already encoded with it.
+  pub fn set_codec(&self, codec: common::Codec) {
+    *self.codec.write().unwrap() = codec;
+
+    let mut writer = self.writer.lock().unwrap();
+    if let Some(ref mut writer) = *writer {
+      let mut write_codec = self.write_codec.write().unwrap();
+      let msg = common::Message::Request {
+        id: self.next_request(),
+        message: Box::new(common::Message::SetCodec { codec }),
+      };
+      let compression = *self.compression.read().unwrap();
+      if let Err(e) = common::write(writer, *write_codec, compression, msg) {
         log::error!("write {}", e.to_string());
+        return;
       }
+      self.stats.sent.add_message();
+      *write_codec = codec;
+    }
+  }
+
+  /// Asks the server to compress frames above `threshold` bytes, `None` turns compression off for both sides.
+  pub fn set_compression(&self, threshold: Option<u32>) {
+    *self.compression.write().unwrap() = threshold;
+    self.send(common::Message::SetCompression { threshold });
+  }
+
+  /// Closes the current connection and connects to `server`.
+  pub fn connect(&self, server: String) {
+    self.disconnect();
+    log::info!("connect to {}", server);
+    *self.server.write().unwrap() = server;
+    self.set_state(ConnectionState::Connecting);
+    self.establish_connection(Duration::from_secs(0));
+  }
+
+  /// Closes the connection and removes every synced node, connecting again is possible afterwards.
+  pub fn disconnect(&self) {
+    self.connection.fetch_add(1, Ordering::SeqCst);
+
+    let writer = self.writer.lock().unwrap().take();
+    if let Some(writer) = writer {
+      log::info!("disconnect");
+      if let Err(e) = writer.shutdown_stream() {
+        log::warn!("disconnect {}", e);
+      }
+      self.emit(events::Events::Disconnected);
+    }
+
+    self.clear();
+    self.room.write().unwrap().take();
+    self.set_state(ConnectionState::Disconnected);
+  }
+
+  /// Destroys the local copies of the synced nodes, which belong to the scene they were spawned in.
+  pub fn forget_nodes(&self) {
+    let ids: Vec<String> = self.synced_nodes.read().unwrap().keys().cloned().collect();
+    for id in ids {
+      self.destroy_node(&id);
+    }
+  }
+
+  /// Forgets everything which belongs to the closed connection.
+  fn clear(&self) {
+    self.forget_nodes();
+    self.spawn_requests.write().unwrap().clear();
+    self.stats.set_rtt(None);
+    self.clock.lock().unwrap().reset();
+    self.join_requests.write().unwrap().clear();
+    self.lockstep.write().unwrap().take();
+
+    // spawns waiting for a confirmation would block forever
+    for (_, pair) in self.waiting.write().unwrap().drain() {
+      *pair.0.lock().unwrap() = Some(Err("disconnected".to_owned()));
+      pair.1.notify_one();
     }
   }
 
+  /// Joins `scene`, which leaves the room joined before.
+  pub fn join(&self, scene: String, password: Option<String>) -> u64 {
+    let request = self.next_request();
+    self.join_requests.write().unwrap().insert(request, (scene.clone(), password.clone()));
+    self.send_request(request, common::Message::Join { scene, password });
+    request
+  }
+
+  /// Leaves the current room, the server destroys the entities this client spawned there.
+  pub fn leave_room(&self) {
+    self.join_requests.write().unwrap().clear();
+    self.lockstep.write().unwrap().take();
+    let room = self.room.write().unwrap().take();
+    if let Some((scene, _)) = room {
+      self.send(common::Message::Leave { scene });
+    }
+  }
+
+  /// The server sent the manifest of `scene`, so a join succeeded.
+  fn joined(&self, scene: &str) {
+    let mut requests = self.join_requests.write().unwrap();
+    let request = requests.iter()
+      .find(|(_, (s, _))| s == scene)
+      .map(|(request, _)| *request);
+
+    if let Some(request) = request {
+      *self.room.write().unwrap() = requests.remove(&request);
+    }
+
+    // a room in lockstep sends its state right after the manifest
+    self.lockstep.write().unwrap().take();
+  }
+
+  /// Sends the input of this client for the tick `input_delay` ticks ahead, returns that tick.
+  fn send_input(&self, scene: String, data: Vec<u8>) -> Result<u64, String> {
+    let tick = match self.lockstep.read().unwrap().as_ref() {
+      Some(lockstep) if lockstep.scene == scene => lockstep.tick + lockstep.config.input_delay as u64,
+      _ => return Err(format!("room {} is not in lockstep", scene)),
+    };
+    self.send(common::Message::Input { scene, tick, data });
+    Ok(tick)
+  }
+
+  pub fn set_server(&self, server: String) {
+    *self.server.write().unwrap() = server;
+  }
+
   pub fn shutdown(&self) {
     self.running.store(false, Ordering::SeqCst);
     let mut writer = self.writer.lock().unwrap();
     if let Some(ref mut writer) = *writer {
-      writer.shutdown(std::net::Shutdown::Both).unwrap();
+      if let Err(e) = writer.shutdown_stream() {
+        log::warn!("shutdown {}", e);
+      }
     }
   }
 }
 
 impl mlua::UserData for Network {
   fn add_methods<'lua, M: mlua::UserDataMethods<'lua, Self>>(methods: &mut M) {
-    methods.add_method("spawn", |_, this, (scene, drawable, behave): (String, String, Option<String>)| {
-      let id = nanoid::nanoid!(32);
-      let pair = {
-        let mut waiters = this.waiting.write().unwrap();
-        let pair = Arc::new((Mutex::new(None), Condvar::new()));
-        waiters.insert(id.clone(), pair.clone());
-        pair
+    methods.add_method("spawn", |_, this, (scene, drawable, behavior, parent): (String, String, Option<String>, Option<String>)| {
+      let options = SpawnOptions { behavior, parent, ..SpawnOptions::default() };
+      this.spawn(scene, drawable, options)
+    });
+
+    // options: behavior, parent, transform (matrix), material (taken from any node) and timeout in milliseconds
+    methods.add_method("spawn_with", |_, this, (scene, drawable, options): (String, String, mlua::Table)| {
+      this.spawn(scene, drawable, SpawnOptions::from_table(&options)?)
+    });
+
+    // returns right away, `callback(node, error)` is called by the update loop once the spawn is done
+    methods.add_method("spawn_async", |lua, this, (scene, drawable, options, callback): (String, String, Option<mlua::Table>, Option<mlua::Function>)| {
+      let options = match options {
+        Some(options) => SpawnOptions::from_table(&options)?,
+        None => SpawnOptions::default(),
       };
+      let pending = this.request_spawn(scene, drawable, options);
 
-      this.send(common::Message::Spawn {
-        id: id.clone(),
-        scene: scene,
-        drawable: drawable,
-        behavior: behave,
-      });
-
-      let (lock, cvar) = &*pair;
-      let mut opt_node = lock.lock().unwrap();
-      while opt_node.is_none() {
-        opt_node = cvar.wait(opt_node).unwrap();
+      if let Some(callback) = callback {
+        let callbacks = match lua.named_registry_value::<_, Option<mlua::Table>>(SPAWN_CALLBACKS)? {
+          Some(callbacks) => callbacks,
+          None => {
+            let callbacks = lua.create_table()?;
+            lua.set_named_registry_value(SPAWN_CALLBACKS, callbacks.clone())?;
+            callbacks
+          }
+        };
+        callbacks.set(callbacks.raw_len() + 1, lua.create_sequence_from(vec![
+          mlua::Value::UserData(lua.create_userdata(pending.clone())?),
+          mlua::Value::Function(callback),
+        ])?)?;
       }
 
+      Ok(pending)
+    });
+
+    // without a host the last server is connected to again
+    methods.add_method("connect", |_, this, (host, port): (Option<String>, Option<u16>)| {
+      let server = match host {
+        Some(host) => format!("{}:{}", host, port.unwrap_or(config::DEFAULT_PORT)),
+        None => this.server.read().unwrap().clone(),
+      };
+      this.connect(server);
+
+      Ok(())
+    });
+
+    methods.add_method("disconnect", |_, this, (): ()| {
+      this.disconnect();
+
+      Ok(())
+    });
+
+    methods.add_method("server", |_, this, (): ()| {
+      Ok(this.server.read().unwrap().clone())
+    });
+
+    methods.add_method("id", |_, this, (): ()| {
+      Ok(this.user.id().to_owned())
+    });
+
+    // traffic is per second, rtt is nil until the first ping was answered
+    methods.add_method("stats", |lua, this, (): ()| {
+      let rates = this.stats.rates();
+      let stats = lua.create_table()?;
+
+      stats.set("bytes_sent", rates.bytes_sent)?;
+      stats.set("bytes_received", rates.bytes_received)?;
+      stats.set("messages_sent", rates.messages_sent)?;
+      stats.set("messages_received", rates.messages_received)?;
+      stats.set("total_bytes_sent", this.stats.sent.bytes())?;
+      stats.set("total_bytes_received", this.stats.received.bytes())?;
+      stats.set("rtt", this.stats.rtt())?;
+      // packet loss is only known once there are unreliable channels, over tcp nothing gets lost
+      stats.set("synced", this.synced_nodes.read().unwrap().len())?;
+      stats.set("owned", this.owned.read().unwrap().len())?;
+      stats.set("pending_spawns", this.waiting.read().unwrap().len())?;
+      stats.set("state", this.state().name())?;
+
+      Ok(stats)
+    });
+
+    // milliseconds since the unix epoch, the same for every player, nil until the clock is synchronized
+    methods.add_method("server_time", |_, this, (): ()| {
+      Ok(this.server_time())
+    });
+
+    methods.add_method("state", |_, this, (): ()| {
+      Ok(this.state().name())
+    });
+
+    methods.add_method("join", |_, this, (scene, password): (String, Option<String>)| {
+      Ok(this.join(scene, password))
+    });
+
+    methods.add_method("leave", |_, this, scene: String| {
       {
-        let mut waiters = this.waiting.write().unwrap();
-        waiters.remove(&id);
+        let mut room = this.room.write().unwrap();
+        if matches!(room.as_ref(), Some((s, _)) if *s == scene) {
+          room.take();
+        }
       }
+      Ok(this.send(common::Message::Leave { scene: scene }))
+    });
+
+    // `lockstep` is a table with tick_ms and input_delay, the room then relays inputs instead of transforms
+    methods.add_method("create_room", |_, this, (name, max_players, password, persistent, lockstep): (String, Option<u32>, Option<String>, Option<bool>, Option<mlua::Table>)| {
+      let lockstep = match lockstep {
+        Some(table) => Some(common::LockstepConfig {
+          tick_ms: table.get::<_, Option<u32>>("tick_ms")?.unwrap_or(50),
+          input_delay: table.get::<_, Option<u32>>("input_delay")?.unwrap_or(2),
+        }),
+        None => None,
+      };
+
+      Ok(this.send(common::Message::CreateRoom {
+        name: name,
+        max_players: max_players.unwrap_or(0),
+        password: password,
+        persistent: persistent.unwrap_or(false),
+        lockstep: lockstep,
+      }))
+    });
+
+    // queues `data` for a later tick of a room in lockstep, returns the tick
+    methods.add_method("send_input", |_, this, (scene, data): (String, mlua::String)| {
+      this.send_input(scene, data.as_bytes().to_vec())
+        .map_err(|e| error::to_lua_err(&e))
+    });
+
+    methods.add_method("send_checksum", |_, this, (scene, tick, checksum): (String, u64, u32)| {
+      Ok(this.send(common::Message::Checksum { scene, tick, checksum }))
+    });
 
-      Ok(NodeUserData { node: opt_node.clone().unwrap() })
+    // FNV-1a of `data`, to compare the simulations of the players
+    methods.add_method("checksum", |_, _, data: mlua::String| {
+      let checksum = data.as_bytes().iter()
+        .fold(0x811c9dc5u32, |hash, b| (hash ^ *b as u32).wrapping_mul(0x01000193));
+      Ok(checksum)
     });
 
-    methods.add_method("join", |_, this, scene: String| {
-      this.send(common::Message::Join { scene: scene });
+    methods.add_method("interpolation_delay", |_, this, (): ()| {
+      Ok(this.interpolation_delay.load(Ordering::SeqCst))
+    });
+
+    methods.add_method("set_interpolation_delay", |_, this, ms: u64| {
+      this.interpolation_delay.store(ms, Ordering::SeqCst);
 
       Ok(())
     });
 
-    methods.add_method("destroy", |_, this, (scene, id): (String, String)| {
-      this.send(common::Message::Destroy { scene: scene, id: id });
+    methods.add_method("set_codec", |_, this, name: String| {
+      let codec = common::Codec::from_name(&name)
+        .ok_or_else(|| error::to_lua_err(&format!("unknown codec {}", name)))?;
+      this.set_codec(codec);
+
+      Ok(())
+    });
+
+    methods.add_method("set_compression", |_, this, threshold: Option<u32>| {
+      this.set_compression(threshold);
 
       Ok(())
     });
+
+    methods.add_method("asset_path", |_, this, path: String| {
+      let assets = this.assets.lock().unwrap();
+      Ok(assets.path(&path).map(|file| file.to_string_lossy().into_owned()))
+    });
+
+    methods.add_method("list_rooms", |_, this, (): ()| {
+      Ok(this.send(common::Message::ListRooms))
+    });
+
+    methods.add_method("list_offenders", |_, this, (): ()| {
+      Ok(this.send(common::Message::ListOffenders))
+    });
+
+    methods.add_method("destroy", |_, this, (scene, id): (String, String)| {
+      Ok(this.send(common::Message::Destroy { scene: scene, id: id }))
+    });
   }
 }
 
+/// Calls the callbacks of every finished `spawn_async` made by the state `lua`.
+pub fn call_spawn_callbacks(lua: &mlua::Lua) -> mlua::Result<()> {
+  let callbacks = match lua.named_registry_value::<_, Option<mlua::Table>>(SPAWN_CALLBACKS)? {
+    Some(callbacks) => callbacks,
+    None => return Ok(()),
+  };
+
+  let mut done = Vec::new();
+  let mut remaining = Vec::new();
+  for entry in callbacks.sequence_values::<mlua::Table>() {
+    let entry = entry?;
+    let pending: mlua::AnyUserData = entry.get(1)?;
+    let result = pending.borrow::<PendingSpawn>()?.poll();
+    match result {
+      Some(result) => done.push((entry.get::<_, mlua::Function>(2)?, result)),
+      None => remaining.push(entry),
+    }
+  }
+
+  if done.is_empty() {
+    return Ok(());
+  }
+  lua.set_named_registry_value(SPAWN_CALLBACKS, lua.create_sequence_from(remaining)?)?;
+
+  for (callback, result) in done {
+    match result {
+      Ok(node) => callback.call::<_, ()>((NodeUserData { node: node }, mlua::Value::Nil))?,
+      Err(e) => callback.call::<_, ()>((mlua::Value::Nil, e))?,
+    }
+  }
+
+  Ok(())
+}
+
+fn material_info(m: &ImplMaterial) -> common::MaterialInfo {
+  common::MaterialInfo {
+    ambient: m.ambient,
+    diffuse: m.diffuse,
+    specular: m.specular,
+    shininess: m.shininess,
+  }
+}
+
+fn apply_material(m: &mut ImplMaterial, info: &common::MaterialInfo) {
+  m.ambient = info.ambient;
+  m.diffuse = info.diffuse;
+  m.specular = info.specular;
+  m.shininess = info.shininess;
+}
+
 pub fn new() -> Network {
-  let net = Network {
-    id: nanoid::nanoid!(32),
+  Network {
+    user: Arc::new(User::load().expect("could not load user key")),
     writer: Arc::new(Mutex::new(None)),
+    write_codec: Arc::new(RwLock::new(common::Codec::default())),
+    codec: Arc::new(RwLock::new(common::Codec::default())),
+    compression: Arc::new(RwLock::new(Some(common::compression::DEFAULT_THRESHOLD))),
     synced_nodes: Arc::new(RwLock::new(HashMap::new())),
+    buffers: Arc::new(RwLock::new(HashMap::new())),
+    assets: Arc::new(Mutex::new(Assets::new())),
+    interpolation_delay: Arc::new(AtomicU64::new(100)),
     owned: Arc::new(RwLock::new(HashMap::new())),
     waiting: Arc::new(RwLock::new(HashMap::new())),
+    spawn_requests: Arc::new(RwLock::new(HashMap::new())),
+    next_request: Arc::new(AtomicU64::new(1)),
+    server: Arc::new(RwLock::new(format!("127.0.0.1:{}", config::DEFAULT_PORT))),
+    connection: Arc::new(AtomicU64::new(0)),
+    state: Arc::new(RwLock::new(ConnectionState::Disconnected)),
+    room: Arc::new(RwLock::new(None)),
+    join_requests: Arc::new(RwLock::new(HashMap::new())),
+    lockstep: Arc::new(RwLock::new(None)),
+    stats: Arc::new(Stats::new()),
+    clock: Arc::new(Mutex::new(Clock::new())),
+    started: Instant::now(),
     running: Arc::new(AtomicBool::new(true)),
-  };
-
-  net.establish_connection();
-
-  net
+  }
 }
\ No newline at end of file
@@ -0,0 +1,101 @@
+use std::collections::VecDeque;
+
+use crate::methatron::math::matrix;
+
+/// Inputs kept for replay, about three seconds of transform updates.
+const MAX_PENDING: usize = 64;
+
+/// Largest difference of a matrix element that still counts as a correct prediction.
+const EPSILON: f32 = 0.001;
+
+struct Input {
+  seq: u64,
+  /// Transform of the node after the input was applied.
+  predicted: [f32; 16],
+  /// Change since the previous input, applied from the left.
+  delta: [f32; 16],
+}
+
+/// Remembers the transforms an owned node sent to the server until they are acknowledged.
+/// When the server corrects one of them, the inputs sent afterwards are replayed on top of the correction,
+/// so the node keeps its local movement instead of snapping back.
+pub struct Prediction {
+  next_seq: u64,
+  last_sent: Option<[f32; 16]>,
+  pending: VecDeque<Input>,
+}
+
+impl Prediction {
+  pub fn new() -> Prediction {
+    Prediction {
+      next_seq: 1,
+      last_sent: None,
+      pending: VecDeque::new(),
+    }
+  }
+
+  /// Records `transform` as the next input and returns its sequence number.
+  pub fn record(&mut self, transform: &[f32; 16]) -> u64 {
+    let seq = self.next_seq;
+    self.next_seq += 1;
+
+    let delta = match self.last_sent {
+      Some(ref last) => delta(last, transform),
+      None => identity(),
+    };
+
+    self.pending.push_back(Input { seq, predicted: *transform, delta });
+    self.last_sent = Some(*transform);
+
+    while self.pending.len() > MAX_PENDING {
+      self.pending.pop_front();
+    }
+
+    seq
+  }
+
+  /// Handles the acknowledgement of `seq`, returns the new transform of the node if the server corrected it.
+  /// `current` is the transform of the node right now, including movement which was not sent yet.
+  pub fn reconcile(&mut self, seq: u64, authoritative: &[f32; 16], current: &[f32; 16]) -> Option<[f32; 16]> {
+    while self.pending.front().map_or(false, |input| input.seq < seq) {
+      self.pending.pop_front();
+    }
+
+    let acked = match self.pending.front() {
+      Some(input) if input.seq == seq => self.pending.pop_front().unwrap(),
+      _ => return None,
+    };
+
+    let correct = acked.predicted.iter().zip(authoritative.iter()).all(|(a, b)| (a - b).abs() <= EPSILON);
+    if correct {
+      return None;
+    }
+
+    let unsent = match self.last_sent {
+      Some(ref last) => delta(last, current),
+      None => identity(),
+    };
+
+    let mut t = *authoritative;
+    for input in self.pending.iter_mut() {
+      t = matrix::mul(&input.delta, &t);
+      input.predicted = t;
+    }
+    self.last_sent = Some(t);
+
+    Some(matrix::mul(&unsent, &t))
+  }
+}
+
+fn identity() -> [f32; 16] {
+  let mut m = [0.0; 16];
+  matrix::identity(&mut m);
+  m
+}
+
+/// The transform which moves `from` to `to`.
+fn delta(from: &[f32; 16], to: &[f32; 16]) -> [f32; 16] {
+  let mut inverse = [0.0; 16];
+  matrix::inverse(from, &mut inverse);
+  matrix::mul(to, &inverse)
+}
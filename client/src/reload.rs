@@ -0,0 +1,109 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant, SystemTime};
+
+/// Milliseconds between two checks of the watched files.
+const POLL_INTERVAL: u64 = 500;
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Scripts are only reloaded during development, when enabled in the config.
+pub fn enable(enabled: bool) {
+  ENABLED.store(enabled, Ordering::SeqCst);
+}
+
+/// Notices changed files by their modification time.
+pub struct Watcher {
+  files: HashMap<PathBuf, Option<SystemTime>>,
+  checked: Instant,
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+  std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+impl Watcher {
+  pub fn new() -> Watcher {
+    Watcher {
+      files: HashMap::new(),
+      checked: Instant::now(),
+    }
+  }
+
+  /// Whether reloading is enabled and the files should be checked again.
+  pub fn is_due(&mut self) -> bool {
+    if !ENABLED.load(Ordering::SeqCst) || self.checked.elapsed() < Duration::from_millis(POLL_INTERVAL) {
+      return false;
+    }
+    self.checked = Instant::now();
+    true
+  }
+
+  pub fn watch(&mut self, path: &Path) {
+    if !self.files.contains_key(path) {
+      self.files.insert(path.to_owned(), modified(path));
+    }
+  }
+
+  /// Files modified since the previous call.
+  pub fn changed(&mut self) -> Vec<PathBuf> {
+    let mut changed = Vec::new();
+    for (path, time) in self.files.iter_mut() {
+      let current = modified(path);
+      // a file which is being saved may be gone for a moment
+      if current.is_some() && current != *time {
+        *time = current;
+        changed.push(path.clone());
+      }
+    }
+    changed
+  }
+}
+
+/// Files of the modules loaded with `require`, by module name.
+pub fn modules(lua: &mlua::Lua) -> mlua::Result<Vec<(String, PathBuf)>> {
+  let package = match lua.globals().get::<_, Option<mlua::Table>>("package")? {
+    Some(package) => package,
+    None => return Ok(Vec::new()),
+  };
+  let searchpath: mlua::Function = package.get("searchpath")?;
+  let path: String = package.get("path")?;
+  let loaded: mlua::Table = package.get("loaded")?;
+
+  let mut modules = Vec::new();
+  for pair in loaded.pairs::<mlua::Value, mlua::Value>() {
+    if let (mlua::Value::String(name), _) = pair? {
+      let name = name.to_str()?.to_owned();
+      if let Some(file) = searchpath.call::<_, Option<String>>((name.clone(), path.clone()))? {
+        modules.push((name, PathBuf::from(file)));
+      }
+    }
+  }
+  Ok(modules)
+}
+
+/// Watches every module loaded with `require`.
+pub fn watch_modules(lua: &mlua::Lua, watcher: &mut Watcher) -> mlua::Result<()> {
+  for (_, file) in modules(lua)? {
+    watcher.watch(&file);
+  }
+  Ok(())
+}
+
+/// Unloads the modules of the `changed` files, so `require` loads them again. Returns whether there were any.
+pub fn forget_modules(lua: &mlua::Lua, changed: &[PathBuf]) -> mlua::Result<bool> {
+  let stale: Vec<String> = modules(lua)?.into_iter()
+    .filter(|(_, file)| changed.contains(file))
+    .map(|(name, _)| name)
+    .collect();
+
+  if !stale.is_empty() {
+    let loaded: mlua::Table = lua.globals().get::<_, mlua::Table>("package")?.get("loaded")?;
+    for name in stale.iter() {
+      log::info!("reload module {}", name);
+      loaded.set(name.as_str(), mlua::Value::Nil)?;
+    }
+  }
+  Ok(!stale.is_empty())
+}
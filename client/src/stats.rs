@@ -0,0 +1,146 @@
+use std::io::{Read, Write};
+use std::sync::{Arc, Mutex, atomic::{AtomicU64, Ordering}};
+use std::time::Instant;
+
+use shadow_of_truth_common::transport::SyncStream;
+
+/// Totals of one direction of the connection.
+#[derive(Default)]
+pub struct Counter {
+  bytes: AtomicU64,
+  messages: AtomicU64,
+}
+
+impl Counter {
+  pub fn add_message(&self) {
+    self.messages.fetch_add(1, Ordering::Relaxed);
+  }
+
+  pub fn bytes(&self) -> u64 {
+    self.bytes.load(Ordering::Relaxed)
+  }
+
+  pub fn messages(&self) -> u64 {
+    self.messages.load(Ordering::Relaxed)
+  }
+}
+
+/// Per second rates of the last sample.
+#[derive(Clone, Copy, Default)]
+pub struct Rates {
+  pub bytes_sent: f64,
+  pub messages_sent: f64,
+  pub bytes_received: f64,
+  pub messages_received: f64,
+}
+
+struct Sample {
+  at: Instant,
+  /// Bytes and messages sent until `at`.
+  sent: (u64, u64),
+  received: (u64, u64),
+  rates: Rates,
+}
+
+/// Round trip time of an unanswered connection.
+const UNKNOWN_RTT: u64 = u64::MAX;
+
+pub struct Stats {
+  pub sent: Counter,
+  pub received: Counter,
+  /// Milliseconds of the last answered ping.
+  rtt: AtomicU64,
+  sample: Mutex<Sample>,
+}
+
+impl Stats {
+  pub fn new() -> Stats {
+    Stats {
+      sent: Counter::default(),
+      received: Counter::default(),
+      rtt: AtomicU64::new(UNKNOWN_RTT),
+      sample: Mutex::new(Sample {
+        at: Instant::now(),
+        sent: (0, 0),
+        received: (0, 0),
+        rates: Rates::default(),
+      }),
+    }
+  }
+
+  pub fn rtt(&self) -> Option<u64> {
+    match self.rtt.load(Ordering::Relaxed) {
+      UNKNOWN_RTT => None,
+      rtt => Some(rtt),
+    }
+  }
+
+  pub fn set_rtt(&self, rtt: Option<u64>) {
+    self.rtt.store(rtt.unwrap_or(UNKNOWN_RTT), Ordering::Relaxed);
+  }
+
+  /// Rates since the previous sample, a new sample is taken at most once a second.
+  pub fn rates(&self) -> Rates {
+    let mut sample = self.sample.lock().unwrap();
+    let elapsed = sample.at.elapsed().as_secs_f64();
+
+    if elapsed >= 1.0 {
+      let sent = (self.sent.bytes(), self.sent.messages());
+      let received = (self.received.bytes(), self.received.messages());
+
+      sample.rates = Rates {
+        bytes_sent: (sent.0 - sample.sent.0) as f64 / elapsed,
+        messages_sent: (sent.1 - sample.sent.1) as f64 / elapsed,
+        bytes_received: (received.0 - sample.received.0) as f64 / elapsed,
+        messages_received: (received.1 - sample.received.1) as f64 / elapsed,
+      };
+      sample.at = Instant::now();
+      sample.sent = sent;
+      sample.received = received;
+    }
+
+    sample.rates
+  }
+}
+
+/// Counts the bytes read from and written to `stream`, its clones count into the same `Stats`.
+pub struct Counted {
+  stream: Box<dyn SyncStream>,
+  stats: Arc<Stats>,
+}
+
+impl Counted {
+  pub fn new(stream: Box<dyn SyncStream>, stats: Arc<Stats>) -> Counted {
+    Counted { stream, stats }
+  }
+}
+
+impl Read for Counted {
+  fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+    let n = self.stream.read(buf)?;
+    self.stats.received.bytes.fetch_add(n as u64, Ordering::Relaxed);
+    Ok(n)
+  }
+}
+
+impl Write for Counted {
+  fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+    let n = self.stream.write(buf)?;
+    self.stats.sent.bytes.fetch_add(n as u64, Ordering::Relaxed);
+    Ok(n)
+  }
+
+  fn flush(&mut self) -> std::io::Result<()> {
+    self.stream.flush()
+  }
+}
+
+impl SyncStream for Counted {
+  fn try_clone_stream(&self) -> std::io::Result<Box<dyn SyncStream>> {
+    Ok(Box::new(Counted::new(self.stream.try_clone_stream()?, self.stats.clone())))
+  }
+
+  fn shutdown_stream(&self) -> std::io::Result<()> {
+    self.stream.shutdown_stream()
+  }
+}
@@ -1,11 +1,9 @@
+use std::error::Error;
+use std::path::PathBuf;
+
 use log::info;
 
-use openssl::rand::rand_bytes;
-use openssl::sign::{Signer, Verifier};
-use openssl::rsa::{Rsa, Padding};
-use openssl::pkey::{PKey, Public, Private};
-use openssl::hash::MessageDigest;
-use openssl::symm::{decrypt, encrypt, Cipher};
+use shadow_of_truth_common::keys::{self, PrivateKey};
 
 #[derive(Debug)]
 struct KeyNotFoundError {}
@@ -22,10 +20,14 @@ impl Error for KeyNotFoundError {
   }
 }
 
-fn look_for_key() -> Result<String, Box<dyn Error>> {
+fn key_dir() -> Result<PathBuf, Box<dyn Error>> {
   let mut exe = std::env::current_exe()?;
   exe.pop();
-  let parent = exe.as_path();
+  Ok(exe)
+}
+
+fn look_for_key() -> Result<PathBuf, Box<dyn Error>> {
+  let parent = key_dir()?;
 
   info!("look for key in: {:?}", parent);
 
@@ -34,19 +36,49 @@ fn look_for_key() -> Result<String, Box<dyn Error>> {
     let name = e.file_name().into_string().unwrap();
     if name.ends_with(".key") {
       info!("found key: {}", name);
-      return Ok(name)
+      return Ok(e.path())
     }
   }
 
   Err(Box::new(KeyNotFoundError{}))
 }
 
+/// The persistent identity of the player, its id is the fingerprint of the public key.
 pub struct User {
-  cert: PKey<Private>,
+  key: PrivateKey,
+  public_key: Vec<u8>,
+  id: String,
 }
 
 impl User {
-  pub fn new () -> User {
-    User {}
+  /// Loads the key next to the executable, or generates one on the first start.
+  pub fn load() -> Result<User, Box<dyn Error>> {
+    let path = match look_for_key() {
+      Ok(path) => path,
+      Err(_) => key_dir()?.join("user.key"),
+    };
+    let key = keys::optain_private_key(&path)?;
+    let public_key = keys::public_key(&key)?;
+    let id = keys::fingerprint(&public_key);
+
+    info!("user id: {}", id);
+
+    Ok(User {
+      key,
+      public_key,
+      id,
+    })
+  }
+
+  pub fn id(&self) -> &str {
+    &self.id
+  }
+
+  pub fn public_key(&self) -> Vec<u8> {
+    self.public_key.clone()
   }
-}
\ No newline at end of file
+
+  pub fn sign(&self, data: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
+    keys::sign(&self.key, data)
+  }
+}
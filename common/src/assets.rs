@@ -0,0 +1,21 @@
+use std::path::{Component, Path, PathBuf};
+
+/// Bytes of an asset sent in one `Message::AssetChunk`.
+pub const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Hex encoded sha256 of the content of an asset.
+pub fn hash(data: &[u8]) -> String {
+  openssl::sha::sha256(data).iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Joins the asset `path` to `dir`, `None` when the path would leave `dir`.
+/// Asset paths come from the other side of the connection and must never point outside of the asset directory.
+pub fn resolve(dir: &Path, path: &str) -> Option<PathBuf> {
+  let relative = Path::new(path);
+  if relative.components().all(|c| matches!(c, Component::Normal(_))) {
+    Some(dir.join(relative))
+  }
+  else {
+    None
+  }
+}
@@ -0,0 +1,42 @@
+use serde::{Serialize, Deserialize};
+
+use crate::Message;
+
+/// Encoding of the frames on the wire.
+///
+/// Every connection starts with `Cbor` and may switch with `Message::SetCodec`.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+pub enum Codec {
+  #[default]
+  Cbor,
+  MessagePack,
+  /// Human readable, meant for debugging.
+  Json,
+}
+
+impl Codec {
+  pub fn from_name(name: &str) -> Option<Codec> {
+    match name {
+      "cbor" => Some(Codec::Cbor),
+      "msgpack" | "messagepack" => Some(Codec::MessagePack),
+      "json" => Some(Codec::Json),
+      _ => None,
+    }
+  }
+
+  pub fn encode(&self, msg: &Message) -> Result<Vec<u8>, String> {
+    match self {
+      Codec::Cbor => serde_cbor::to_vec(msg).map_err(|e| e.to_string()),
+      Codec::MessagePack => rmp_serde::to_vec(msg).map_err(|e| e.to_string()),
+      Codec::Json => serde_json::to_vec(msg).map_err(|e| e.to_string()),
+    }
+  }
+
+  pub fn decode(&self, data: &[u8]) -> Result<Message, String> {
+    match self {
+      Codec::Cbor => serde_cbor::from_slice(data).map_err(|e| e.to_string()),
+      Codec::MessagePack => rmp_serde::from_slice(data).map_err(|e| e.to_string()),
+      Codec::Json => serde_json::from_slice(data).map_err(|e| e.to_string()),
+    }
+  }
+}
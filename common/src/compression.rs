@@ -0,0 +1,91 @@
+use std::io::{Read, Write};
+
+use flate2::{read::DeflateDecoder, write::DeflateEncoder, Compression};
+
+/// Set in the length prefix of a frame whose payload is deflate compressed.
+pub const COMPRESSED: u32 = 1 << 31;
+
+/// Frames larger than this are compressed unless the peer asked for another threshold.
+pub const DEFAULT_THRESHOLD: u32 = 512;
+
+/// Largest payload of a frame, compressed or not, so a peer can not make the other side allocate gigabytes.
+pub const MAX_FRAME: usize = 16 * 1024 * 1024;
+
+pub fn compress(data: &[u8]) -> Result<Vec<u8>, String> {
+  let mut encoder = DeflateEncoder::new(Vec::new(), Compression::fast());
+  encoder.write_all(data).map_err(|e| e.to_string())?;
+  encoder.finish().map_err(|e| e.to_string())
+}
+
+/// Fails for payloads which inflate beyond `MAX_FRAME`.
+pub fn decompress(data: &[u8]) -> Result<Vec<u8>, String> {
+  let mut decoded = Vec::new();
+  DeflateDecoder::new(data).take(MAX_FRAME as u64 + 1).read_to_end(&mut decoded).map_err(|e| e.to_string())?;
+  if decoded.len() > MAX_FRAME {
+    return Err(format!("payload inflates beyond {} bytes", MAX_FRAME));
+  }
+  Ok(decoded)
+}
+
+/// Builds the length prefix and payload of a frame, compressing `data` when it is larger than `threshold`.
+/// The compressed payload is only used when it is actually smaller.
+pub fn frame(data: Vec<u8>, threshold: Option<u32>) -> Result<([u8; 4], Vec<u8>), String> {
+  if let Some(threshold) = threshold {
+    if data.len() > threshold as usize {
+      let compressed = compress(&data)?;
+      if compressed.len() < data.len() {
+        return Ok((((compressed.len() as u32) | COMPRESSED).to_le_bytes(), compressed));
+      }
+    }
+  }
+
+  Ok(((data.len() as u32).to_le_bytes(), data))
+}
+
+/// Splits a length prefix into the payload size and whether the payload is compressed.
+pub fn header(size_buffer: [u8; 4]) -> (usize, bool) {
+  let size = u32::from_le_bytes(size_buffer);
+  ((size & !COMPRESSED) as usize, size & COMPRESSED != 0)
+}
+
+pub fn payload(data: Vec<u8>, compressed: bool) -> Result<Vec<u8>, String> {
+  if compressed {
+    decompress(&data)
+  }
+  else {
+    Ok(data)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn frame_round_trip() {
+    let data = vec![7u8; 4096];
+    let (size_buffer, payload_data) = frame(data.clone(), Some(DEFAULT_THRESHOLD)).unwrap();
+    let (size, compressed) = header(size_buffer);
+
+    assert!(compressed);
+    assert_eq!(size, payload_data.len());
+    assert_eq!(payload(payload_data, compressed).unwrap(), data);
+  }
+
+  #[test]
+  fn small_frames_stay_uncompressed() {
+    let (size_buffer, payload_data) = frame(vec![1, 2, 3], Some(DEFAULT_THRESHOLD)).unwrap();
+
+    assert_eq!(header(size_buffer), (3, false));
+    assert_eq!(payload_data, vec![1, 2, 3]);
+  }
+
+  #[test]
+  fn decompress_rejects_oversize_payloads() {
+    let bomb = compress(&vec![0u8; MAX_FRAME + 1]).unwrap();
+
+    assert!(bomb.len() < MAX_FRAME / 100);
+    assert!(decompress(&bomb).is_err());
+    assert_eq!(decompress(&compress(&vec![0u8; MAX_FRAME]).unwrap()).unwrap().len(), MAX_FRAME);
+  }
+}
@@ -2,10 +2,14 @@ use std::error::Error;
 use std::fs::File;
 use std::io::{Read, Write};
 
-use openssl::rsa::{Rsa, Padding};
+use openssl::hash::MessageDigest;
+use openssl::rsa::Rsa;
 use openssl::pkey::{PKey, Public, Private};
+use openssl::sign::{Signer, Verifier};
 
-type PrivateResult = Result<PKey<Private>, Box<dyn Error>>;
+pub type PrivateKey = PKey<Private>;
+
+type PrivateResult = Result<PrivateKey, Box<dyn Error>>;
 
 pub fn optain_private_key(path: &std::path::Path) -> PrivateResult {
   if path.exists() {
@@ -36,4 +40,33 @@ fn load_key(path: &std::path::Path) -> PrivateResult {
   file.read_to_end(&mut buffer)?;
 
   Ok(PKey::private_key_from_pem(&buffer)?)
-}
\ No newline at end of file
+}
+
+/// DER encoded public half of `key`, as sent on login.
+pub fn public_key(key: &PrivateKey) -> Result<Vec<u8>, Box<dyn Error>> {
+  Ok(key.public_key_to_der()?)
+}
+
+/// Hex encoded SHA-256 of a DER encoded public key, used as the identity of a user.
+pub fn fingerprint(public_key: &[u8]) -> String {
+  openssl::sha::sha256(public_key).iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+pub fn nonce() -> Result<Vec<u8>, Box<dyn Error>> {
+  let mut buffer = vec![0u8; 32];
+  openssl::rand::rand_bytes(&mut buffer)?;
+  Ok(buffer)
+}
+
+pub fn sign(key: &PrivateKey, data: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
+  let mut signer = Signer::new(MessageDigest::sha256(), key)?;
+  signer.update(data)?;
+  Ok(signer.sign_to_vec()?)
+}
+
+pub fn verify(public_key: &[u8], data: &[u8], signature: &[u8]) -> Result<bool, Box<dyn Error>> {
+  let key: PKey<Public> = PKey::public_key_from_der(public_key)?;
+  let mut verifier = Verifier::new(MessageDigest::sha256(), &key)?;
+  verifier.update(data)?;
+  Ok(verifier.verify(signature)?)
+}
@@ -1,29 +1,250 @@
 use std::io::{Read, Write, ErrorKind};
 
 use serde::{Serialize, Deserialize};
+pub use codec::Codec;
 use tokio::{
     io::{
+      AsyncRead,
       AsyncReadExt,
+      AsyncWrite,
       AsyncWriteExt,
     },
-    net::{
-        tcp::{
-          OwnedReadHalf,
-          OwnedWriteHalf,
-        },
-    },
 };
 
+pub mod assets;
+pub mod codec;
+pub mod compression;
 pub mod keys;
+pub mod transport;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RoomInfo {
+  pub name: String,
+  pub players: u32,
+  pub max_players: u32,
+  pub has_password: bool,
+  pub persistent: bool,
+  /// Set for rooms which relay inputs instead of transforms.
+  #[serde(default)]
+  pub lockstep: Option<LockstepConfig>,
+}
+
+/// Rooms in lockstep only relay the inputs of every tick, which the clients simulate deterministically.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct LockstepConfig {
+  /// Milliseconds between two ticks.
+  pub tick_ms: u32,
+  /// Ticks between sampling an input and simulating it, which hides the latency.
+  pub input_delay: u32,
+}
+
+/// The input a player sent for a tick.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct PlayerInput {
+  pub player: String,
+  #[serde(with = "serde_bytes")]
+  pub data: Vec<u8>,
+}
+
+/// A user whose movements the server corrected.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Offender {
+  pub id: String,
+  pub violations: u32,
+  /// Reached the `flag_after` violations of a room.
+  pub flagged: bool,
+}
+
+/// A file a room needs, `path` is relative to the asset directory.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct AssetInfo {
+  pub path: String,
+  pub hash: String,
+  pub size: u64,
+}
+
+/// Material parameters of a spawned node.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct MaterialInfo {
+  pub ambient: [f32; 3],
+  pub diffuse: [f32; 3],
+  pub specular: [f32; 3],
+  pub shininess: f32,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum ErrorCode {
+  UnknownMessage,
+  NotLoggedIn,
+  AlreadyLoggedIn,
+  UnknownRoom,
+  RoomExists,
+  RoomFull,
+  WrongPassword,
+  NotInRoom,
+  NotOwner,
+  AuthenticationFailed,
+  Banned,
+  NotAllowed,
+  UnknownEntity,
+  /// Entity ids are unique within a room, a spawn can not replace an entity.
+  EntityExists,
+  /// Inputs and checksums are only accepted by rooms in lockstep, transforms only by the others.
+  WrongRoomMode,
+  /// The tick of an input is too far ahead of the server.
+  InvalidTick,
+  UnknownAsset,
+  /// The worker hosting the room can not be reached by the gateway.
+  WorkerUnavailable,
+}
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum Message {
-  Login{id: String},
-  Join{scene: String},
+  /// Wraps a client message so the server can refer to it in an `Error` reply.
+  Request{id: u64, message: Box<Message>},
+  Error{code: ErrorCode, reason: String, in_reply_to: Option<u64>},
+  /// Every following message of the sender is encoded with `codec`.
+  SetCodec{codec: Codec},
+  /// Acknowledges `SetCodec`, every following message of the server is encoded with `codec`.
+  CodecChanged{codec: Codec},
+  /// Allows the server to compress frames larger than `threshold` bytes, `None` disables compression.
+  /// Compressed frames are always accepted, so only the sender needs to know about it.
+  SetCompression{threshold: Option<u32>},
+  /// Acknowledges `SetCompression`.
+  CompressionChanged{threshold: Option<u32>},
+  /// Answered right away with a `Pong` carrying the same `time`, so the sender can measure the round trip.
+  Ping{time: u64},
+  /// `server_time` is the wall clock of the server in milliseconds since the unix epoch when answering,
+  /// which lets the client estimate the offset of its own clock.
+  Pong{time: u64, server_time: u64},
+  /// Several messages in one frame, so large batches benefit from compression.
+  Batch{messages: Vec<Message>},
+  /// Starts the login with the DER encoded public key of the user.
+  Login{
+    #[serde(with = "serde_bytes")]
+    public_key: Vec<u8>,
+  },
+  /// The server asks the client to prove it owns the private key by signing `nonce`.
+  Challenge{
+    #[serde(with = "serde_bytes")]
+    nonce: Vec<u8>,
+  },
+  Authenticate{
+    #[serde(with = "serde_bytes")]
+    signature: Vec<u8>,
+  },
+  /// Login succeeded, `id` is the fingerprint of the public key.
+  Welcome{id: String},
+  /// Sent by a gateway to log in a user it already authenticated on another worker, answered with `Welcome`.
+  /// `secret` has to match the `gateway_secret` of the worker.
+  Handoff{id: String, secret: String},
+  CreateRoom{
+    name: String,
+    max_players: u32,
+    password: Option<String>,
+    persistent: bool,
+    #[serde(default)]
+    lockstep: Option<LockstepConfig>,
+  },
+  ListRooms,
+  Rooms{rooms: Vec<RoomInfo>},
+  Join{scene: String, password: Option<String>},
   Leave{scene: String},
-  Spawn{id: String, scene: String, drawable: String, behavior: Option<String>},
+  /// `parent` is the network id of the entity the new one is attached to, destroying it destroys its children too.
+  /// The server keeps `transform` up to date for clients joining later.
+  Spawn{
+    id: String,
+    scene: String,
+    drawable: String,
+    behavior: Option<String>,
+    parent: Option<String>,
+    transform: Option<[f32; 16]>,
+    material: Option<MaterialInfo>,
+    /// Id of the client which spawned the entity, set by the server.
+    #[serde(default)]
+    owner: Option<String>,
+  },
   Destroy{id: String, scene: String},
-  TransformUpdate{scene: String, id: String, t: [f32; 16]},
+  /// Assets required by `scene`, sent after joining it.
+  Manifest{scene: String, assets: Vec<AssetInfo>},
+  /// Asks for the content of an asset of the manifest, which is answered with `AssetChunk`s.
+  FetchAsset{path: String},
+  /// `data` starts at `offset` of the asset with `total` bytes.
+  AssetChunk{
+    path: String,
+    offset: u64,
+    total: u64,
+    #[serde(with = "serde_bytes")]
+    data: Vec<u8>,
+  },
+  /// Sent to every client before the server goes down, `reconnect_after` is in seconds.
+  ServerShutdown{reason: String, reconnect_after: Option<u64>},
+  /// Sent after joining a room in lockstep, `tick` is the next tick the server sends.
+  LockstepState{scene: String, tick: u64, config: LockstepConfig},
+  /// Input of the sender for `tick`, inputs arriving too late are moved to the next tick sent.
+  /// A second input for the same tick replaces the first.
+  Input{
+    scene: String,
+    tick: u64,
+    #[serde(with = "serde_bytes")]
+    data: Vec<u8>,
+  },
+  /// Every input of `tick` ordered by player, clients simulate the tick once they received it.
+  Tick{scene: String, tick: u64, inputs: Vec<PlayerInput>},
+  /// Checksum of the simulation state of the sender after `tick`.
+  Checksum{scene: String, tick: u64, checksum: u32},
+  /// The checksums of `tick` disagree, which means the simulations diverged.
+  Desync{scene: String, tick: u64, checksums: Vec<(String, u32)>},
+  /// `time` is stamped by the server in milliseconds since it started.
+  /// `seq` numbers the updates of the owner, which takes the relayed update as acknowledgement of its input.
+  TransformUpdate{scene: String, id: String, t: [f32; 16], time: u64, seq: u64},
+  /// Only allowed for admins, answered with `Offenders`.
+  ListOffenders,
+  Offenders{offenders: Vec<Offender>},
+  /// Sent by a gateway on its own connection, the worker handles `message` with the rights of `user`.
+  OnBehalf{user: String, message: Box<Message>},
+}
+
+impl Message {
+  /// Name of the variant, for logging without the payload.
+  pub fn kind(&self) -> &'static str {
+    match self {
+      Message::Request{..} => "Request",
+      Message::Error{..} => "Error",
+      Message::SetCodec{..} => "SetCodec",
+      Message::CodecChanged{..} => "CodecChanged",
+      Message::SetCompression{..} => "SetCompression",
+      Message::CompressionChanged{..} => "CompressionChanged",
+      Message::Ping{..} => "Ping",
+      Message::Pong{..} => "Pong",
+      Message::Batch{..} => "Batch",
+      Message::Login{..} => "Login",
+      Message::Challenge{..} => "Challenge",
+      Message::Authenticate{..} => "Authenticate",
+      Message::Welcome{..} => "Welcome",
+      Message::Handoff{..} => "Handoff",
+      Message::CreateRoom{..} => "CreateRoom",
+      Message::ListRooms => "ListRooms",
+      Message::Rooms{..} => "Rooms",
+      Message::Join{..} => "Join",
+      Message::Leave{..} => "Leave",
+      Message::Spawn{..} => "Spawn",
+      Message::Destroy{..} => "Destroy",
+      Message::Manifest{..} => "Manifest",
+      Message::FetchAsset{..} => "FetchAsset",
+      Message::AssetChunk{..} => "AssetChunk",
+      Message::ServerShutdown{..} => "ServerShutdown",
+      Message::LockstepState{..} => "LockstepState",
+      Message::Input{..} => "Input",
+      Message::Tick{..} => "Tick",
+      Message::Checksum{..} => "Checksum",
+      Message::Desync{..} => "Desync",
+      Message::TransformUpdate{..} => "TransformUpdate",
+      Message::ListOffenders => "ListOffenders",
+      Message::Offenders{..} => "Offenders",
+      Message::OnBehalf{..} => "OnBehalf",
+    }
+  }
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -44,7 +265,25 @@ pub enum Command {
   },
 }
 
-pub fn read<T: Read>(read: &mut T) -> Result<Option<Message>, Box<dyn std::error::Error>> {
+#[derive(Debug)]
+pub enum ReadError {
+  Io(String),
+  /// The frame was read completely but could not be decoded, so the stream is still usable.
+  Decode(String),
+}
+
+impl std::fmt::Display for ReadError {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self {
+      ReadError::Io(e) => write!(f, "io: {}", e),
+      ReadError::Decode(e) => write!(f, "decode: {}", e),
+    }
+  }
+}
+
+impl std::error::Error for ReadError {}
+
+pub fn read<T: Read>(read: &mut T, codec: Codec) -> Result<Option<Message>, Box<dyn std::error::Error>> {
     let mut size_buffer = [0u8; 4];
     match read.read_exact(&mut size_buffer) {
       Ok(_) => {}
@@ -53,8 +292,11 @@ pub fn read<T: Read>(read: &mut T) -> Result<Option<Message>, Box<dyn std::error
       Err(e) => { return Err(e.into()) }
     }
 
-    let size = u32::from_le_bytes(size_buffer);
-    let mut data = vec![0u8; size as usize];
+    let (size, compressed) = compression::header(size_buffer);
+    if size > compression::MAX_FRAME {
+      return Err(ReadError::Io(format!("frame of {} bytes", size)).into());
+    }
+    let mut data = vec![0u8; size];
     match read.read_exact(&mut data) {
       Ok(_) => {}
       Err(ref e) if e.kind() == ErrorKind::BrokenPipe => { return Ok(None) }
@@ -62,12 +304,13 @@ pub fn read<T: Read>(read: &mut T) -> Result<Option<Message>, Box<dyn std::error
       Err(e) => { return Err(e.into()) }
     }
 
-    Ok(serde_cbor::from_slice(&data)?)
+    let data = compression::payload(data, compressed).map_err(ReadError::Decode)?;
+    Ok(Some(codec.decode(&data).map_err(ReadError::Decode)?))
 }
 
-pub fn write<T: Write>(write: &mut T, msg: Message) -> Result<Option<()>, Box<dyn std::error::Error>> {
-    let data = serde_cbor::to_vec(&msg)?;
-    let size_buffer = (data.len() as u32).to_le_bytes();
+/// Frames larger than `compress_above` bytes are compressed, `None` never compresses.
+pub fn write<T: Write>(write: &mut T, codec: Codec, compress_above: Option<u32>, msg: Message) -> Result<Option<()>, Box<dyn std::error::Error>> {
+    let (size_buffer, data) = compression::frame(codec.encode(&msg)?, compress_above)?;
 
     match write.write(&size_buffer) {
       Ok(_) => {}
@@ -83,32 +326,35 @@ pub fn write<T: Write>(write: &mut T, msg: Message) -> Result<Option<()>, Box<dy
     Ok(Some(()))
 }
 
-pub async fn async_read(read: &mut OwnedReadHalf) -> Result<Option<Message>, String> {
+pub async fn async_read<T: AsyncRead + Unpin>(read: &mut T, codec: Codec) -> Result<Option<Message>, ReadError> {
 
     let mut size_buffer = [0u8; 4];
     match read.read_exact(&mut size_buffer).await {
       Ok(_) => {}
       Err(ref e) if e.kind() == ErrorKind::BrokenPipe => { return Ok(None) }
       Err(ref e) if e.kind() == ErrorKind::UnexpectedEof => { return Ok(None) }
-      Err(e) => { return Err(e.to_string()) }
+      Err(e) => { return Err(ReadError::Io(e.to_string())) }
     }
 
-    let size = u32::from_le_bytes(size_buffer);
-    let mut data = vec![0u8; size as usize];
+    let (size, compressed) = compression::header(size_buffer);
+    if size > compression::MAX_FRAME {
+      return Err(ReadError::Io(format!("frame of {} bytes", size)));
+    }
+    let mut data = vec![0u8; size];
     match read.read_exact(&mut data).await {
       Ok(_) => {}
       Err(ref e) if e.kind() == ErrorKind::BrokenPipe => { return Ok(None) }
       Err(ref e) if e.kind() == ErrorKind::UnexpectedEof => { return Ok(None) }
-      Err(e) => { return Err(e.to_string()) }
+      Err(e) => { return Err(ReadError::Io(e.to_string())) }
     }
 
-    let msg = serde_cbor::from_slice(&data).map_err(|e| e.to_string())?;
+    let data = compression::payload(data, compressed).map_err(ReadError::Decode)?;
+    let msg = codec.decode(&data).map_err(ReadError::Decode)?;
     Ok(Some(msg))
 }
 
-pub async fn async_write(write: &mut OwnedWriteHalf, msg: Message) -> Result<Option<()>, String> {
-    let data = serde_cbor::to_vec(&msg).map_err(|e| e.to_string())?;
-    let size_buffer = (data.len() as u32).to_le_bytes();
+pub async fn async_write<T: AsyncWrite + Unpin>(write: &mut T, codec: Codec, compress_above: Option<u32>, msg: Message) -> Result<Option<()>, String> {
+    let (size_buffer, data) = compression::frame(codec.encode(&msg)?, compress_above)?;
 
     match write.write(&size_buffer).await {
       Ok(_) => {}
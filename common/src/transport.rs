@@ -0,0 +1,274 @@
+//! Connections the protocol can run over.
+//!
+//! The async `Reader`/`Writer` pair is used by the server for every kind of connection,
+//! while clients use a blocking `SyncStream`.
+
+use std::io::{Read, Write};
+
+use async_trait::async_trait;
+use futures_util::{
+  sink::SinkExt,
+  stream::{SplitSink, SplitStream, StreamExt},
+};
+use tokio::{
+  io::{AsyncRead, AsyncWrite, AsyncWriteExt},
+  net::TcpStream,
+  sync::mpsc,
+};
+use tokio_tungstenite::{tungstenite::Message as WsMessage, WebSocketStream};
+
+use crate::{Codec, Message, ReadError};
+
+#[async_trait]
+pub trait Reader: Send {
+  /// Returns `Ok(None)` once the other side closed the connection.
+  async fn read(&mut self) -> Result<Option<Message>, ReadError>;
+
+  /// Decodes every following message with `codec`.
+  fn set_codec(&mut self, codec: Codec);
+}
+
+#[async_trait]
+pub trait Writer: Send {
+  /// Returns `Ok(None)` when the other side is gone.
+  async fn write(&mut self, msg: Message) -> Result<Option<()>, String>;
+
+  async fn shutdown(&mut self) -> Result<(), String>;
+
+  /// Encodes every following message with `codec`.
+  fn set_codec(&mut self, codec: Codec);
+
+  /// Compresses every following message larger than `threshold` bytes, where the transport supports it.
+  fn set_compression(&mut self, threshold: Option<u32>);
+}
+
+pub type Transport = (Box<dyn Reader>, Box<dyn Writer>);
+
+/// Any byte stream, framed with a length prefix.
+pub struct StreamReader<R>(R, Codec);
+
+#[async_trait]
+impl<R: AsyncRead + Unpin + Send> Reader for StreamReader<R> {
+  async fn read(&mut self) -> Result<Option<Message>, ReadError> {
+    crate::async_read(&mut self.0, self.1).await
+  }
+
+  fn set_codec(&mut self, codec: Codec) {
+    self.1 = codec;
+  }
+}
+
+pub struct StreamWriter<W>(W, Codec, Option<u32>);
+
+#[async_trait]
+impl<W: AsyncWrite + Unpin + Send> Writer for StreamWriter<W> {
+  async fn write(&mut self, msg: Message) -> Result<Option<()>, String> {
+    crate::async_write(&mut self.0, self.1, self.2, msg).await
+  }
+
+  async fn shutdown(&mut self) -> Result<(), String> {
+    self.0.shutdown().await.map_err(|e| e.to_string())
+  }
+
+  fn set_codec(&mut self, codec: Codec) {
+    self.1 = codec;
+  }
+
+  fn set_compression(&mut self, threshold: Option<u32>) {
+    self.2 = threshold;
+  }
+}
+
+pub fn tcp(stream: TcpStream) -> Transport {
+  let (read, write) = stream.into_split();
+  (Box::new(StreamReader(read, Codec::default())), Box::new(StreamWriter(write, Codec::default(), None)))
+}
+
+#[cfg(unix)]
+pub fn unix(stream: tokio::net::UnixStream) -> Transport {
+  let (read, write) = stream.into_split();
+  (Box::new(StreamReader(read, Codec::default())), Box::new(StreamWriter(write, Codec::default(), None)))
+}
+
+/// Every websocket message carries exactly one encoded `Message`, so no length prefix is needed.
+/// JSON is sent as text messages, so it can be read in the developer tools of a browser.
+/// Frames are never compressed, as the websocket framing has no room for the flag.
+pub struct WebSocketReader<S>(SplitStream<WebSocketStream<S>>, Codec);
+
+#[async_trait]
+impl<S: AsyncRead + AsyncWrite + Unpin + Send> Reader for WebSocketReader<S> {
+  async fn read(&mut self) -> Result<Option<Message>, ReadError> {
+    loop {
+      match self.0.next().await {
+        Some(Ok(WsMessage::Binary(data))) => {
+          return Ok(Some(self.1.decode(&data).map_err(ReadError::Decode)?));
+        }
+        Some(Ok(WsMessage::Text(data))) => {
+          return Ok(Some(self.1.decode(data.as_bytes()).map_err(ReadError::Decode)?));
+        }
+        Some(Ok(WsMessage::Close(_))) | None => return Ok(None),
+        Some(Ok(_)) => {}
+        Some(Err(e)) => return Err(ReadError::Io(e.to_string())),
+      }
+    }
+  }
+
+  fn set_codec(&mut self, codec: Codec) {
+    self.1 = codec;
+  }
+}
+
+pub struct WebSocketWriter<S>(SplitSink<WebSocketStream<S>, WsMessage>, Codec);
+
+#[async_trait]
+impl<S: AsyncRead + AsyncWrite + Unpin + Send> Writer for WebSocketWriter<S> {
+  async fn write(&mut self, msg: Message) -> Result<Option<()>, String> {
+    let data = self.1.encode(&msg)?;
+    let frame = match self.1 {
+      Codec::Json => WsMessage::text(String::from_utf8(data).map_err(|e| e.to_string())?),
+      _ => WsMessage::binary(data),
+    };
+    self.0.send(frame).await.map_err(|e| e.to_string())?;
+    Ok(Some(()))
+  }
+
+  async fn shutdown(&mut self) -> Result<(), String> {
+    self.0.close().await.map_err(|e| e.to_string())
+  }
+
+  fn set_codec(&mut self, codec: Codec) {
+    self.1 = codec;
+  }
+
+  fn set_compression(&mut self, _: Option<u32>) {}
+}
+
+/// Performs the websocket handshake on an accepted tcp connection.
+pub async fn accept_websocket(stream: TcpStream) -> Result<Transport, String> {
+  let ws = tokio_tungstenite::accept_async(stream).await.map_err(|e| e.to_string())?;
+  let (sink, stream) = ws.split();
+  Ok((Box::new(WebSocketReader(stream, Codec::default())), Box::new(WebSocketWriter(sink, Codec::default()))))
+}
+
+pub struct MemoryReader(mpsc::Receiver<Message>);
+
+#[async_trait]
+impl Reader for MemoryReader {
+  async fn read(&mut self) -> Result<Option<Message>, ReadError> {
+    Ok(self.0.recv().await)
+  }
+
+  fn set_codec(&mut self, _: Codec) {}
+}
+
+pub struct MemoryWriter(Option<mpsc::Sender<Message>>);
+
+#[async_trait]
+impl Writer for MemoryWriter {
+  async fn write(&mut self, msg: Message) -> Result<Option<()>, String> {
+    match &self.0 {
+      Some(tx) => Ok(tx.send(msg).await.ok()),
+      None => Ok(None),
+    }
+  }
+
+  async fn shutdown(&mut self) -> Result<(), String> {
+    self.0 = None;
+    Ok(())
+  }
+
+  fn set_codec(&mut self, _: Codec) {}
+
+  fn set_compression(&mut self, _: Option<u32>) {}
+}
+
+/// Two connected in-memory transports, messages are passed without encoding them.
+pub fn memory(buffer: usize) -> (Transport, Transport) {
+  let (a_tx, a_rx) = mpsc::channel(buffer);
+  let (b_tx, b_rx) = mpsc::channel(buffer);
+
+  let a: Transport = (Box::new(MemoryReader(a_rx)), Box::new(MemoryWriter(Some(b_tx))));
+  let b: Transport = (Box::new(MemoryReader(b_rx)), Box::new(MemoryWriter(Some(a_tx))));
+
+  (a, b)
+}
+
+/// A blocking stream which can be cloned into a reader and a writer half.
+pub trait SyncStream: Read + Write + Send {
+  fn try_clone_stream(&self) -> std::io::Result<Box<dyn SyncStream>>;
+
+  fn shutdown_stream(&self) -> std::io::Result<()>;
+}
+
+impl SyncStream for std::net::TcpStream {
+  fn try_clone_stream(&self) -> std::io::Result<Box<dyn SyncStream>> {
+    Ok(Box::new(self.try_clone()?))
+  }
+
+  fn shutdown_stream(&self) -> std::io::Result<()> {
+    self.shutdown(std::net::Shutdown::Both)
+  }
+}
+
+#[cfg(unix)]
+impl SyncStream for std::os::unix::net::UnixStream {
+  fn try_clone_stream(&self) -> std::io::Result<Box<dyn SyncStream>> {
+    Ok(Box::new(self.try_clone()?))
+  }
+
+  fn shutdown_stream(&self) -> std::io::Result<()> {
+    self.shutdown(std::net::Shutdown::Both)
+  }
+}
+
+/// Connects to `address`, which is either `host:port` or `unix:<path>`.
+pub fn connect(address: &str) -> std::io::Result<Box<dyn SyncStream>> {
+  #[cfg(unix)]
+  {
+    if let Some(path) = address.strip_prefix("unix:") {
+      return Ok(Box::new(std::os::unix::net::UnixStream::connect(path)?));
+    }
+  }
+
+  Ok(Box::new(std::net::TcpStream::connect(address)?))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[tokio::test]
+  async fn memory_passes_messages_both_ways() {
+    let ((mut a_read, mut a_write), (mut b_read, mut b_write)) = memory(4);
+
+    a_write.write(Message::Ping { time: 1 }).await.unwrap();
+    b_write.write(Message::Ping { time: 2 }).await.unwrap();
+
+    assert!(matches!(b_read.read().await, Ok(Some(Message::Ping { time: 1 }))));
+    assert!(matches!(a_read.read().await, Ok(Some(Message::Ping { time: 2 }))));
+  }
+
+  #[tokio::test]
+  async fn memory_shutdown_closes_the_other_side() {
+    let ((_, mut a_write), (mut b_read, _)) = memory(4);
+
+    a_write.write(Message::Ping { time: 1 }).await.unwrap();
+    a_write.shutdown().await.unwrap();
+
+    assert!(matches!(b_read.read().await, Ok(Some(Message::Ping { time: 1 }))));
+    assert!(matches!(b_read.read().await, Ok(None)));
+    assert!(matches!(a_write.write(Message::Ping { time: 2 }).await, Ok(None)));
+  }
+
+  #[tokio::test]
+  async fn stream_transport_round_trip() {
+    let (a, b) = tokio::io::duplex(64 * 1024);
+    let mut writer = StreamWriter(a, Codec::default(), None);
+    let mut reader = StreamReader(b, Codec::default());
+
+    writer.set_compression(Some(0));
+    writer.write(Message::Ping { time: 3 }).await.unwrap();
+
+    assert!(matches!(reader.read().await, Ok(Some(Message::Ping { time: 3 }))));
+  }
+}
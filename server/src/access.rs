@@ -0,0 +1,45 @@
+use std::collections::HashSet;
+
+use log::info;
+use serde::{Serialize, Deserialize};
+
+/// Ban and allow lists of user fingerprints.
+#[derive(Serialize, Deserialize, Default)]
+pub struct AccessList {
+  #[serde(default)]
+  pub banned: HashSet<String>,
+  /// When not empty, only these fingerprints may login.
+  #[serde(default)]
+  pub allowed: HashSet<String>,
+  /// May create persistent rooms.
+  #[serde(default)]
+  pub admins: HashSet<String>,
+}
+
+impl AccessList {
+  pub fn is_banned(&self, fingerprint: &str) -> bool {
+    self.banned.contains(fingerprint)
+  }
+
+  pub fn is_allowed(&self, fingerprint: &str) -> bool {
+    self.allowed.is_empty() || self.allowed.contains(fingerprint)
+  }
+
+  pub fn is_admin(&self, fingerprint: &str) -> bool {
+    self.admins.contains(fingerprint)
+  }
+}
+
+pub fn load(path: &str) -> Result<AccessList, Box<dyn std::error::Error>> {
+  let path = std::path::Path::new(path);
+
+  if path.exists() {
+    info!("load access list");
+
+    let data = std::fs::read_to_string(path)?;
+    Ok(toml::from_str(&data)?)
+  }
+  else {
+    Ok(AccessList::default())
+  }
+}
@@ -0,0 +1,55 @@
+use std::path::Path;
+
+use shadow_of_truth_common::{assets, AssetInfo, Message};
+
+use crate::{handler, RwClient};
+
+/// Hashes the assets `paths` in `dir`, assets which can not be read are left out.
+pub async fn manifest(dir: &Path, paths: &[String]) -> Vec<AssetInfo> {
+  let mut manifest = Vec::with_capacity(paths.len());
+
+  for path in paths {
+    let file = match assets::resolve(dir, path) {
+      Some(file) => file,
+      None => {
+        log::error!("asset {} is outside of the asset directory", path);
+        continue;
+      }
+    };
+
+    match tokio::fs::read(&file).await {
+      Ok(data) => manifest.push(AssetInfo {
+        path: path.clone(),
+        hash: assets::hash(&data),
+        size: data.len() as u64,
+      }),
+      Err(e) => log::error!("asset {} {}", path, e),
+    }
+  }
+
+  manifest
+}
+
+/// Sends the content of the asset `path` in `dir` in chunks.
+pub async fn send(client: &RwClient, dir: &Path, path: &str) -> Result<(), String> {
+  let file = assets::resolve(dir, path).ok_or_else(|| format!("invalid asset path {}", path))?;
+  let data = tokio::fs::read(&file).await.map_err(|e| e.to_string())?;
+  let total = data.len() as u64;
+
+  let mut offset = 0;
+  for chunk in data.chunks(assets::CHUNK_SIZE) {
+    handler::send(client, Message::AssetChunk {
+      path: path.to_owned(),
+      offset,
+      total,
+      data: chunk.to_vec(),
+    }).await;
+    offset += chunk.len() as u64;
+  }
+
+  if data.is_empty() {
+    handler::send(client, Message::AssetChunk{path: path.to_owned(), offset: 0, total: 0, data: Vec::new()}).await;
+  }
+
+  Ok(())
+}
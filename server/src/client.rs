@@ -26,6 +26,8 @@ impl AesKey {
 
 pub enum ClientState {
   Greeting,
+  /// Waiting for the signature of `nonce` made with the key of `public_key`.
+  Challenged{public_key: Vec<u8>, nonce: Vec<u8>},
   SecretSharing,
   Listening,
   Disconnected,
@@ -1,14 +1,88 @@
 use log::info;
 use serde::{Serialize, Deserialize};
 
+use shadow_of_truth_common::LockstepConfig;
+
+use crate::movement::MovementLimits;
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct RoomConfig {
+  pub name: String,
+  #[serde(default)]
+  pub max_players: u32,
+  pub password: Option<String>,
+  #[serde(default)]
+  pub limits: MovementLimits,
+  /// Files clients need in this room, relative to the asset directory.
+  #[serde(default)]
+  pub assets: Vec<String>,
+  /// Relays inputs in lockstep instead of transforms, when set.
+  pub lockstep: Option<LockstepConfig>,
+}
+
+/// Turns the process into a gateway, which hosts no rooms itself.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct GatewayConfig {
+  /// Addresses of the worker servers the rooms are distributed over.
+  pub workers: Vec<String>,
+  /// Has to match the `gateway_secret` of every worker.
+  pub secret: String,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+struct RoomsFile {
+  rooms: Vec<RoomConfig>,
+}
+
+fn default_rooms_file() -> String {
+  "data/rooms.toml".to_owned()
+}
+
+fn default_access_file() -> String {
+  "data/access.toml".to_owned()
+}
+
+fn default_assets_dir() -> String {
+  "data/assets".to_owned()
+}
+
+fn default_shutdown_timeout() -> u64 {
+  5
+}
+
 #[derive(Serialize, Deserialize, Clone)]
 pub struct Config {
   pub port: u16,
+  /// Port for browser based tools and spectators, disabled when not set.
+  pub websocket_port: Option<u16>,
+  /// Path of a unix domain socket for local connections, disabled when not set.
+  pub unix_socket: Option<String>,
   pub private_key: String,
+  /// Where persistent rooms created at runtime are stored on shutdown.
+  #[serde(default = "default_rooms_file")]
+  pub rooms_file: String,
+  /// Ban and allow lists by user fingerprint.
+  #[serde(default = "default_access_file")]
+  pub access_file: String,
+  /// Directory of the assets rooms advertise to clients.
+  #[serde(default = "default_assets_dir")]
+  pub assets_dir: String,
+  /// Seconds the shutdown sequence may take before the process exits anyway.
+  #[serde(default = "default_shutdown_timeout")]
+  pub shutdown_timeout: u64,
+  /// Seconds after which clients are told to reconnect when the server shuts down.
+  pub reconnect_after: Option<u64>,
+  /// Allows a gateway knowing this secret to hand off clients, handoffs are rejected when not set.
+  pub gateway_secret: Option<String>,
+  /// Routes clients to worker servers instead of hosting rooms, when set.
+  pub gateway: Option<GatewayConfig>,
+  /// Rooms which are created on startup and never garbage collected.
+  #[serde(default)]
+  pub rooms: Vec<RoomConfig>,
 }
 
-pub fn load() -> Result<Config, Box<dyn std::error::Error>> {
-  let path = std::path::Path::new("data/config.toml");
+pub fn load(path: &str) -> Result<Config, Box<dyn std::error::Error>> {
+  let path = std::path::Path::new(path);
 
   if path.exists() {
     info!("load config");
@@ -21,11 +95,54 @@ pub fn load() -> Result<Config, Box<dyn std::error::Error>> {
 
     let config = Config {
       port: 3000,
+      websocket_port: None,
+      unix_socket: None,
       private_key: "data/server.key".to_owned(),
+      rooms_file: default_rooms_file(),
+      access_file: default_access_file(),
+      assets_dir: default_assets_dir(),
+      shutdown_timeout: default_shutdown_timeout(),
+      reconnect_after: None,
+      gateway_secret: None,
+      gateway: None,
+      rooms: vec![
+        RoomConfig {
+          name: "main".to_owned(),
+          max_players: 0,
+          password: None,
+          limits: MovementLimits::default(),
+          assets: Vec::new(),
+          lockstep: None,
+        },
+      ],
     };
     let data = toml::to_string_pretty(&config)?;
     std::fs::write(path, data)?;
 
     Ok(config)
   }
-}
\ No newline at end of file
+}
+
+pub fn load_rooms(path: &str) -> Result<Vec<RoomConfig>, Box<dyn std::error::Error>> {
+  let path = std::path::Path::new(path);
+
+  if path.exists() {
+    info!("load rooms");
+
+    let data = std::fs::read_to_string(path)?;
+    let file: RoomsFile = toml::from_str(&data)?;
+    Ok(file.rooms)
+  }
+  else {
+    Ok(Vec::new())
+  }
+}
+
+pub fn save_rooms(path: &str, rooms: Vec<RoomConfig>) -> Result<(), Box<dyn std::error::Error>> {
+  info!("save {} rooms", rooms.len());
+
+  let data = toml::to_string_pretty(&RoomsFile { rooms })?;
+  std::fs::write(path, data)?;
+
+  Ok(())
+}
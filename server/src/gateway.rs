@@ -0,0 +1,388 @@
+//! A gateway accepts the clients and routes each room to one of several worker servers.
+//!
+//! Every client is connected to exactly one worker at a time. Logins go to a worker picked in turn,
+//! joining a room hosted by another worker moves the client over with a `Handoff`.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, atomic::{AtomicUsize, Ordering}};
+use std::time::Duration;
+
+use tokio::{
+  net::{TcpListener, TcpStream},
+  sync::{mpsc::{channel, Sender}, RwLock},
+  task::JoinHandle,
+};
+
+use shadow_of_truth_common::{
+  transport::{self, Transport, Writer},
+  ErrorCode, Message, ReadError, RoomInfo,
+};
+
+use crate::{config::{Config, GatewayConfig}, handler::Rejection, Connection};
+
+/// User the gateway logs in as for listing and creating rooms, fingerprints of users never look like it.
+pub const GATEWAY_ID: &str = "gateway";
+
+/// Connects to the worker at `address` and logs in `id`, which the worker takes for granted.
+async fn connect(address: &str, id: &str, secret: &str) -> Result<Transport, String> {
+  let stream = TcpStream::connect(address).await.map_err(|e| format!("{} {}", address, e))?;
+  let (mut read, mut write) = transport::tcp(stream);
+
+  write.write(Message::Handoff{id: id.to_owned(), secret: secret.to_owned()}).await?;
+  match read.read().await {
+    Ok(Some(Message::Welcome{..})) => Ok((read, write)),
+    Ok(Some(Message::Error{reason, ..})) => Err(format!("handoff to {} {}", address, reason)),
+    Ok(Some(m)) => Err(format!("handoff to {} answered with {:?}", address, m)),
+    Ok(None) => Err(format!("{} closed the connection", address)),
+    Err(e) => Err(format!("{} {}", address, e)),
+  }
+}
+
+fn unavailable(reason: impl Into<String>) -> Rejection {
+  Rejection::new(ErrorCode::WorkerUnavailable, reason)
+}
+
+struct Worker {
+  address: String,
+  /// Connection of the gateway itself, opened on first use.
+  control: tokio::sync::Mutex<Option<Transport>>,
+}
+
+impl Worker {
+  /// Sends `msg` followed by `ListRooms` and returns the rooms of the worker.
+  /// The worker processes the messages of a connection in order, so an `Error` before the `Rooms` belongs to `msg`.
+  async fn request(&self, secret: &str, msg: Option<Message>) -> Result<Vec<RoomInfo>, Rejection> {
+    let mut control = self.control.lock().await;
+    if control.is_none() {
+      *control = Some(connect(&self.address, GATEWAY_ID, secret).await.map_err(unavailable)?);
+    }
+
+    let result = Worker::exchange(control.as_mut().unwrap(), msg).await;
+    if let Err(e) = &result {
+      if e.code == ErrorCode::WorkerUnavailable {
+        // reconnect on the next request
+        *control = None;
+      }
+    }
+    result
+  }
+
+  async fn exchange((read, write): &mut Transport, msg: Option<Message>) -> Result<Vec<RoomInfo>, Rejection> {
+    if let Some(msg) = msg {
+      write.write(msg).await.map_err(unavailable)?;
+    }
+    write.write(Message::ListRooms).await.map_err(unavailable)?;
+
+    let mut rejection = None;
+    loop {
+      match read.read().await {
+        Ok(Some(Message::Rooms{rooms})) => {
+          return match rejection {
+            Some(rejection) => Err(rejection),
+            None => Ok(rooms),
+          };
+        }
+        Ok(Some(Message::Error{code, reason, ..})) => rejection = Some(Rejection::new(code, reason)),
+        Ok(Some(m)) => log::debug!("ignore {:?} on control connection", m),
+        Ok(None) => return Err(unavailable("worker closed the connection")),
+        Err(e) => return Err(unavailable(e.to_string())),
+      }
+    }
+  }
+}
+
+/// The connection of a client to the worker hosting its room.
+struct Upstream {
+  worker: usize,
+  write: Box<dyn Writer>,
+  /// Forwards the messages of the worker to the client.
+  pump: JoinHandle<()>,
+}
+
+impl Upstream {
+  fn new(worker: usize, (mut read, write): Transport, tx: Sender<Message>, user: Arc<Mutex<Option<String>>>) -> Upstream {
+    let pump = tokio::spawn(async move {
+      loop {
+        match read.read().await {
+          Ok(Some(msg)) => {
+            if let Message::Welcome{id} = &msg {
+              *user.lock().unwrap() = Some(id.clone());
+            }
+            let is_shutdown = matches!(msg, Message::ServerShutdown{..});
+            if tx.send(msg).await.is_err() || is_shutdown {
+              return;
+            }
+          }
+          Ok(None) => break,
+          Err(ReadError::Decode(e)) => log::warn!("worker {}", e),
+          Err(e) => {
+            log::error!("worker {}", e);
+            break;
+          }
+        }
+      }
+      // without its worker the client can not reach its room anymore
+      let msg = Message::ServerShutdown{reason: "lost connection to the worker".to_owned(), reconnect_after: None};
+      if let Err(e) = tx.send(msg).await {
+        log::warn!("shutdown notification {}", e);
+      }
+    });
+
+    Upstream { worker, write, pump }
+  }
+
+  async fn send(&mut self, msg: Message) {
+    if let Err(e) = self.write.write(msg).await {
+      log::warn!("forward to worker {}", e);
+    }
+  }
+
+  /// Closes the connection, which makes the worker remove the client from its room.
+  async fn close(mut self) {
+    self.pump.abort();
+    if let Err(e) = self.write.shutdown().await {
+      log::warn!("worker shutdown {}", e);
+    }
+  }
+}
+
+#[derive(Clone)]
+struct Gateway {
+  workers: Arc<Vec<Worker>>,
+  secret: Arc<String>,
+  /// Worker index of every known room.
+  directory: Arc<RwLock<HashMap<String, usize>>>,
+  /// Worker the next client logs in to, so logins are spread over all workers.
+  next_login: Arc<AtomicUsize>,
+  connections: Arc<Mutex<Vec<Connection>>>,
+}
+
+impl Gateway {
+  fn new(config: GatewayConfig) -> Gateway {
+    let workers = config.workers.into_iter()
+      .map(|address| Worker { address, control: tokio::sync::Mutex::new(None) })
+      .collect();
+
+    Gateway {
+      workers: Arc::new(workers),
+      secret: Arc::new(config.secret),
+      directory: Arc::new(RwLock::new(HashMap::new())),
+      next_login: Arc::new(AtomicUsize::new(0)),
+      connections: Arc::new(Mutex::new(Vec::new())),
+    }
+  }
+
+  async fn update_directory(&self, worker: usize, rooms: &[RoomInfo]) {
+    let mut directory = self.directory.write().await;
+    directory.retain(|_, w| *w != worker);
+    for r in rooms {
+      directory.insert(r.name.clone(), worker);
+    }
+  }
+
+  /// Rooms of all reachable workers.
+  async fn list_rooms(&self) -> Vec<RoomInfo> {
+    let mut all = Vec::new();
+    for (i, worker) in self.workers.iter().enumerate() {
+      match worker.request(&self.secret, None).await {
+        Ok(rooms) => {
+          self.update_directory(i, &rooms).await;
+          all.extend(rooms);
+        }
+        Err(e) => log::warn!("list rooms of {} {}", worker.address, e.reason),
+      }
+    }
+    all
+  }
+
+  async fn locate(&self, scene: &str) -> Option<usize> {
+    if let Some(worker) = self.directory.read().await.get(scene) {
+      return Some(*worker);
+    }
+    // rooms may have been created by the workers themselves
+    self.list_rooms().await;
+    self.directory.read().await.get(scene).copied()
+  }
+
+  /// Creates the room on the worker with the fewest rooms.
+  async fn create_room(&self, name: &str, msg: Message) -> Result<(), Rejection> {
+    let worker = match self.locate(name).await {
+      // let the worker reject the duplicate
+      Some(worker) => worker,
+      None => {
+        let directory = self.directory.read().await;
+        (0..self.workers.len())
+          .min_by_key(|w| directory.values().filter(|d| *d == w).count())
+          .unwrap_or(0)
+      }
+    };
+
+    log::info!("create room {} on {}", name, self.workers[worker].address);
+    let rooms = self.workers[worker].request(&self.secret, Some(msg)).await?;
+    self.update_directory(worker, &rooms).await;
+    Ok(())
+  }
+
+  /// Logs `user` in to `worker` and joins the room there. Returns the connection once the worker sent the manifest,
+  /// otherwise the reply for the client.
+  async fn migrate(&self, worker: usize, user: &str, join: Message, request: Option<u64>, tx: &Sender<Message>) -> Result<Transport, Message> {
+    let address = &self.workers[worker].address;
+    let (mut read, mut write) = connect(address, user, &self.secret).await
+      .map_err(|e| unavailable(e).reply(request))?;
+
+    if let Err(e) = write.write(join).await {
+      return Err(unavailable(e).reply(request));
+    }
+    match read.read().await {
+      Ok(Some(manifest @ Message::Manifest{..})) => {
+        log::info!("move {} to {}", user, address);
+        if let Err(e) = tx.send(manifest).await {
+          log::warn!("send to {} {}", user, e);
+        }
+        Ok((read, write))
+      }
+      Ok(Some(reply)) => Err(reply),
+      Ok(None) => Err(unavailable(format!("{} closed the connection", address)).reply(request)),
+      Err(e) => Err(unavailable(e.to_string()).reply(request)),
+    }
+  }
+
+  fn handle_connection(&self, transport: Transport) {
+    let (mut read, write) = transport;
+    let (tx, rx) = channel(20);
+    let writer = crate::spawn_writer(write, rx);
+
+    {
+      let mut connections = self.connections.lock().unwrap();
+      connections.retain(|(_, writer)| !writer.is_finished());
+      connections.push((tx.clone(), writer));
+    }
+
+    let gateway = self.clone();
+    tokio::spawn(async move {
+      let send = |msg: Message| {
+        let tx = tx.clone();
+        async move {
+          if let Err(e) = tx.send(msg).await {
+            log::warn!("send {}", e);
+          }
+        }
+      };
+
+      // the worker logging the client in also checks the access list
+      let user = Arc::new(Mutex::new(None));
+      let first = gateway.next_login.fetch_add(1, Ordering::SeqCst) % gateway.workers.len();
+      let mut upstream = match TcpStream::connect(&gateway.workers[first].address).await {
+        Ok(stream) => Upstream::new(first, transport::tcp(stream), tx.clone(), user.clone()),
+        Err(e) => {
+          log::error!("worker {} {}", gateway.workers[first].address, e);
+          send(Message::ServerShutdown{reason: "no worker available".to_owned(), reconnect_after: None}).await;
+          return;
+        }
+      };
+
+      loop {
+        let msg = match read.read().await {
+          Ok(Some(msg)) => msg,
+          Ok(None) => break,
+          Err(ReadError::Decode(e)) => {
+            send(Rejection::new(ErrorCode::UnknownMessage, e).reply(None)).await;
+            continue;
+          }
+          Err(e) => {
+            log::error!("read {}", e);
+            break;
+          }
+        };
+
+        let (request, inner) = match &msg {
+          Message::Request{id, message} => (Some(*id), message.as_ref()),
+          m => (None, m),
+        };
+        // until the login succeeded, everything goes to the worker which rejects it
+        let logged_in = user.lock().unwrap().clone();
+
+        match inner {
+          Message::SetCodec{codec} => {
+            read.set_codec(*codec);
+            send(Message::CodecChanged{codec: *codec}).await;
+          }
+          Message::SetCompression{threshold} => {
+            send(Message::CompressionChanged{threshold: *threshold}).await;
+          }
+          Message::ListRooms if logged_in.is_some() => {
+            let rooms = gateway.list_rooms().await;
+            send(Message::Rooms{rooms}).await;
+          }
+          Message::CreateRoom{name, ..} if logged_in.is_some() => {
+            let name = name.clone();
+            // the worker checks the rights of the user, not the ones of the gateway
+            let msg = Message::OnBehalf{user: logged_in.unwrap_or_default(), message: Box::new(inner.clone())};
+            if let Err(rejection) = gateway.create_room(&name, msg).await {
+              send(rejection.reply(request)).await;
+            }
+          }
+          Message::Join{scene, ..} if logged_in.is_some() => {
+            match gateway.locate(scene).await {
+              Some(worker) if worker == upstream.worker => upstream.send(msg).await,
+              Some(worker) => {
+                let id = logged_in.unwrap_or_default();
+                match gateway.migrate(worker, &id, msg, request, &tx).await {
+                  Ok(transport) => {
+                    let previous = std::mem::replace(&mut upstream, Upstream::new(worker, transport, tx.clone(), user.clone()));
+                    previous.close().await;
+                  }
+                  Err(reply) => send(reply).await,
+                }
+              }
+              None => {
+                let rejection = Rejection::new(ErrorCode::UnknownRoom, format!("unknown room {}", scene));
+                send(rejection.reply(request)).await;
+              }
+            }
+          }
+          _ => upstream.send(msg).await,
+        }
+      }
+
+      upstream.close().await;
+    });
+  }
+}
+
+pub async fn run(config: &Config, gateway: GatewayConfig) -> Result<(), Box<dyn std::error::Error>> {
+  if gateway.workers.is_empty() {
+    return Err("no workers configured".into());
+  }
+
+  let gateway = Gateway::new(gateway);
+  let listener = TcpListener::bind(format!("127.0.0.1:{}", config.port)).await?;
+  log::info!("gateway for {} workers listens on {}", gateway.workers.len(), listener.local_addr()?);
+
+  let accept = {
+    let gateway = gateway.clone();
+    tokio::spawn(async move {
+      loop {
+        match listener.accept().await {
+          Ok((stream, addr)) => {
+            log::info!("connection from {:?}", addr);
+            gateway.handle_connection(transport::tcp(stream));
+          }
+          Err(e) => log::error!("listener: {}", e),
+        }
+      }
+    })
+  };
+
+  tokio::signal::ctrl_c().await?;
+  log::info!("shutting down");
+  accept.abort();
+
+  let timeout = Duration::from_secs(config.shutdown_timeout);
+  let notify = crate::notify_shutdown(&gateway.connections, "gateway is shutting down".to_owned(), config.reconnect_after);
+  if tokio::time::timeout(timeout, notify).await.is_err() {
+    log::warn!("clients were not flushed within {}s", config.shutdown_timeout);
+  }
+
+  Ok(())
+}
@@ -0,0 +1,248 @@
+use shadow_of_truth_common::{keys, ErrorCode, Message};
+
+use crate::{assets, client::ClientState, config::RoomConfig, gateway::GATEWAY_ID, movement::MovementLimits, RwClient, ServerContext};
+
+/// Why a client message was not processed, sent back as `Message::Error`.
+#[derive(Debug)]
+pub struct Rejection {
+  pub code: ErrorCode,
+  pub reason: String,
+}
+
+impl Rejection {
+  pub fn new(code: ErrorCode, reason: impl Into<String>) -> Rejection {
+    Rejection {
+      code,
+      reason: reason.into(),
+    }
+  }
+
+  pub fn reply(self, in_reply_to: Option<u64>) -> Message {
+    Message::Error {
+      code: self.code,
+      reason: self.reason,
+      in_reply_to,
+    }
+  }
+}
+
+pub async fn send(client: &RwClient, msg: Message) {
+  let c = client.read().await;
+  if let Err(e) = c.tx.send(msg).await {
+    log::warn!("send to {} {}", c.id, e);
+  }
+}
+
+fn require_room(room: &str, scene: &str) -> Result<(), Rejection> {
+  if room == scene {
+    Ok(())
+  }
+  else {
+    Err(Rejection::new(ErrorCode::NotInRoom, format!("not in room {}", scene)))
+  }
+}
+
+async fn check_access(ctx: &ServerContext, id: &str) -> Result<(), Rejection> {
+  let access = ctx.access.read().await;
+  if access.is_banned(id) {
+    return Err(Rejection::new(ErrorCode::Banned, "banned from this server"));
+  }
+  if !access.is_allowed(id) {
+    return Err(Rejection::new(ErrorCode::NotAllowed, "not on the allow list"));
+  }
+  Ok(())
+}
+
+/// Creates the room of a `CreateRoom` with the rights of `creator`.
+async fn create_room(ctx: &ServerContext, creator: &str, msg: Message) -> Result<(), Rejection> {
+  let (name, max_players, password, persistent, lockstep) = match msg {
+    Message::CreateRoom{name, max_players, password, persistent, lockstep} => (name, max_players, password, persistent, lockstep),
+    m => return Err(Rejection::new(ErrorCode::UnknownMessage, format!("unexpected message {}", m.kind()))),
+  };
+  if persistent && !ctx.access.read().await.is_admin(creator) {
+    return Err(Rejection::new(ErrorCode::NotAllowed, "only admins create persistent rooms"));
+  }
+  let config = RoomConfig {
+    name: name.clone(),
+    max_players,
+    password,
+    limits: MovementLimits::default(),
+    assets: Vec::new(),
+    lockstep,
+  };
+  ctx.create_room(config, persistent).await?;
+  ctx.collect_room_later(name);
+  Ok(())
+}
+
+async fn login(ctx: &ServerContext, client: &RwClient, id: String) {
+  {
+    let mut c = client.write().await;
+    c.id = id.clone();
+    c.state = ClientState::Listening;
+  }
+  ctx.clients.write().await.insert(id.clone(), client.clone());
+  send(client, Message::Welcome{id}).await;
+}
+
+pub async fn handle(ctx: &ServerContext, client: &RwClient, msg: Message) -> Result<(), Rejection> {
+  let (logged_in, room) = {
+    let c = client.read().await;
+    (matches!(c.state, ClientState::Listening), c.room.clone())
+  };
+
+  match msg {
+    Message::Ping{time} => {
+      send(client, Message::Pong{time, server_time: ctx.wall_clock()}).await;
+    }
+    Message::Login{public_key} => {
+      if logged_in {
+        return Err(Rejection::new(ErrorCode::AlreadyLoggedIn, "already logged in"));
+      }
+      let nonce = keys::nonce().map_err(|e| Rejection::new(ErrorCode::AuthenticationFailed, e.to_string()))?;
+      client.write().await.state = ClientState::Challenged{public_key, nonce: nonce.clone()};
+      send(client, Message::Challenge{nonce}).await;
+    }
+    Message::Authenticate{signature} => {
+      if logged_in {
+        return Err(Rejection::new(ErrorCode::AlreadyLoggedIn, "already logged in"));
+      }
+      let public_key = {
+        let mut c = client.write().await;
+        match std::mem::replace(&mut c.state, ClientState::Greeting) {
+          ClientState::Challenged{public_key, nonce} => {
+            match keys::verify(&public_key, &nonce, &signature) {
+              Ok(true) => public_key,
+              Ok(false) => return Err(Rejection::new(ErrorCode::AuthenticationFailed, "invalid signature")),
+              Err(e) => return Err(Rejection::new(ErrorCode::AuthenticationFailed, e.to_string())),
+            }
+          }
+          _ => return Err(Rejection::new(ErrorCode::AuthenticationFailed, "no login in progress")),
+        }
+      };
+
+      let id = keys::fingerprint(&public_key);
+      check_access(ctx, &id).await?;
+
+      log::info!("{} logged in", id);
+      login(ctx, client, id).await;
+    }
+    Message::Handoff{id, secret} => {
+      if logged_in {
+        return Err(Rejection::new(ErrorCode::AlreadyLoggedIn, "already logged in"));
+      }
+      // the gateway authenticated the user, the access lists of the workers may differ though
+      match ctx.gateway_secret.as_ref() {
+        Some(s) if *s == secret => {}
+        _ => return Err(Rejection::new(ErrorCode::AuthenticationFailed, "handoff not accepted")),
+      }
+      // the gateway itself is not a user, so it is not on the access list
+      if id != GATEWAY_ID {
+        check_access(ctx, &id).await?;
+      }
+
+      log::info!("{} handed off by the gateway", id);
+      login(ctx, client, id).await;
+    }
+    _ if !logged_in => {
+      return Err(Rejection::new(ErrorCode::NotLoggedIn, "login first"));
+    }
+    msg @ Message::CreateRoom{..} => {
+      let id = client.read().await.id.clone();
+      create_room(ctx, &id, msg).await?;
+    }
+    Message::OnBehalf{user, message} => {
+      if client.read().await.id != GATEWAY_ID {
+        return Err(Rejection::new(ErrorCode::NotAllowed, "only gateways act on behalf of users"));
+      }
+      // the access lists of the workers may differ from the one of the worker the user logged in to
+      check_access(ctx, &user).await?;
+      create_room(ctx, &user, *message).await?;
+    }
+    Message::ListOffenders => {
+      let id = client.read().await.id.clone();
+      if !ctx.access.read().await.is_admin(&id) {
+        return Err(Rejection::new(ErrorCode::NotAllowed, "only admins list offenders"));
+      }
+      let offenders = ctx.violations.read().await.values().cloned().collect();
+      send(client, Message::Offenders{offenders}).await;
+    }
+    Message::ListRooms => {
+      let rooms = ctx.list_rooms().await;
+      send(client, Message::Rooms{rooms}).await;
+    }
+    Message::Join{scene, password} => {
+      ctx.join_room(client.clone(), scene, password).await?;
+    }
+    Message::Leave{scene} => {
+      require_room(&room, &scene)?;
+      client.write().await.room.clear();
+      ctx.leave_room(client.clone(), &scene).await;
+    }
+    Message::Spawn{id, scene, drawable, behavior, parent, transform, material, ..} => {
+      require_room(&room, &scene)?;
+      if ctx.has_entity(&scene, &id).await {
+        return Err(Rejection::new(ErrorCode::EntityExists, format!("entity {} exists", id)));
+      }
+      if let Some(parent) = &parent {
+        // parents have to exist before their children, so the entities can not form a cycle
+        if parent == &id || !ctx.has_entity(&scene, parent).await {
+          return Err(Rejection::new(ErrorCode::UnknownEntity, format!("unknown parent {}", parent)));
+        }
+      }
+      let client_id = client.read().await.id.clone();
+      let spawn = Message::Spawn{id: id.clone(), scene: scene.clone(), drawable, behavior, parent, transform, material, owner: Some(client_id.clone())};
+      // another client may have spawned the same id meanwhile
+      let spawn = ctx.fill_spawn_cache(&client_id, spawn).await
+        .ok_or_else(|| Rejection::new(ErrorCode::EntityExists, format!("entity {} exists", id)))?;
+      client.write().await.owned_spawns.insert(id);
+      ctx.relay_message(&scene, &spawn).await;
+    }
+    Message::Destroy{id, scene} => {
+      require_room(&room, &scene)?;
+      if !client.read().await.owned_spawns.contains(&id) {
+        return Err(Rejection::new(ErrorCode::NotOwner, format!("{} is not owned", id)));
+      }
+      ctx.destroy_entities(&scene, vec![id]).await;
+    }
+    Message::FetchAsset{path} => {
+      if !ctx.has_asset(&room, &path).await {
+        return Err(Rejection::new(ErrorCode::UnknownAsset, format!("{} is not an asset of room {}", path, room)));
+      }
+      assets::send(client, &ctx.assets_dir, &path).await
+        .map_err(|e| Rejection::new(ErrorCode::UnknownAsset, e))?;
+    }
+    Message::TransformUpdate{scene, id, t, seq, ..} => {
+      // updates are not requests, so they are dropped without a reply instead of flooding the client with errors
+      let (owned, client_id) = {
+        let c = client.read().await;
+        (c.owned_spawns.contains(&id), c.id.clone())
+      };
+      if room == scene && owned && !ctx.is_lockstep(&scene).await {
+        // a corrected transform reaches the owner with its seq, which makes it reconcile its prediction
+        if let Some(t) = ctx.check_movement(&client_id, &scene, &id, &t).await {
+          let msg = Message::TransformUpdate{scene: scene.clone(), id, t, time: ctx.now(), seq};
+          ctx.relay_message(&scene, &msg).await;
+        }
+      }
+      else {
+        log::debug!("drop transform update of {} in {}", id, scene);
+      }
+    }
+    Message::Input{scene, tick, data} => {
+      require_room(&room, &scene)?;
+      let id = client.read().await.id.clone();
+      ctx.lockstep_input(&id, &scene, tick, data).await?;
+    }
+    Message::Checksum{scene, tick, checksum} => {
+      require_room(&room, &scene)?;
+      let id = client.read().await.id.clone();
+      ctx.lockstep_checksum(&id, &scene, tick, checksum).await?;
+    }
+    m => {
+      return Err(Rejection::new(ErrorCode::UnknownMessage, format!("unexpected message {:?}", m)));
+    }
+  }
+
+  Ok(())
+}
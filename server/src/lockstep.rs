@@ -0,0 +1,211 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Weak;
+use std::time::Duration;
+
+use tokio::sync::RwLock;
+
+use shadow_of_truth_common::{LockstepConfig, Message, PlayerInput};
+
+use crate::{handler, room::Room};
+
+/// Ticks an input may be ahead of the server, so clients can not make it buffer without bounds.
+pub const MAX_AHEAD: u64 = 200;
+
+/// Ticks the checksums are kept while players did not report them yet.
+const CHECKSUM_HISTORY: u64 = 100;
+
+/// Milliseconds between two ticks, shorter ticks flood the clients and longer ones stall the game.
+const MIN_TICK_MS: u32 = 10;
+const MAX_TICK_MS: u32 = 1000;
+
+/// Ticks an input may be delayed, the delayed inputs have to fit into `MAX_AHEAD`.
+const MAX_INPUT_DELAY: u32 = 50;
+
+/// Bounds a config which was chosen by a client.
+pub fn clamp(config: LockstepConfig) -> LockstepConfig {
+  LockstepConfig {
+    tick_ms: config.tick_ms.clamp(MIN_TICK_MS, MAX_TICK_MS),
+    input_delay: config.input_delay.min(MAX_INPUT_DELAY),
+  }
+}
+
+/// Inputs and checksums of a room in lockstep.
+pub struct Lockstep {
+  pub config: LockstepConfig,
+  /// The next tick sent to the clients.
+  pub tick: u64,
+  /// Inputs of the upcoming ticks by player, ordered so every client sees the same order.
+  inputs: BTreeMap<u64, BTreeMap<String, Vec<u8>>>,
+  checksums: BTreeMap<u64, HashMap<String, u32>>,
+}
+
+impl Lockstep {
+  pub fn new(config: LockstepConfig) -> Lockstep {
+    Lockstep {
+      config,
+      tick: 0,
+      inputs: BTreeMap::new(),
+      checksums: BTreeMap::new(),
+    }
+  }
+
+  /// Queues the input of `player`, returns false when `tick` is too far ahead.
+  pub fn add_input(&mut self, player: &str, tick: u64, data: Vec<u8>) -> bool {
+    if tick > self.tick + MAX_AHEAD {
+      return false;
+    }
+
+    // a tick which was sent already can not change anymore
+    let tick = tick.max(self.tick);
+    self.inputs.entry(tick).or_default().insert(player.to_owned(), data);
+    true
+  }
+
+  /// Closes the current tick and returns it with its inputs.
+  pub fn advance(&mut self, scene: &str) -> Message {
+    let tick = self.tick;
+    self.tick += 1;
+    // ticks some players never report, because they left, joined later or do not care, are dropped in time
+    self.checksums = self.checksums.split_off(&self.tick.saturating_sub(CHECKSUM_HISTORY));
+
+    let inputs = self.inputs.remove(&tick).unwrap_or_default().into_iter()
+      .map(|(player, data)| PlayerInput { player, data })
+      .collect();
+
+    Message::Tick { scene: scene.to_owned(), tick, inputs }
+  }
+
+  /// Records the checksum of `player`, returns all checksums of `tick` once all `players` reported them and they disagree.
+  pub fn add_checksum(&mut self, player: &str, tick: u64, checksum: u32, players: usize) -> Option<Vec<(String, u32)>> {
+    if tick >= self.tick || tick + CHECKSUM_HISTORY < self.tick {
+      return None;
+    }
+
+    let reported = self.checksums.entry(tick).or_default();
+    reported.insert(player.to_owned(), checksum);
+    if reported.len() < players {
+      return None;
+    }
+
+    let reported = self.checksums.remove(&tick)?;
+
+    let first = *reported.values().next()?;
+    if reported.values().all(|c| *c == first) {
+      return None;
+    }
+
+    let mut checksums: Vec<(String, u32)> = reported.into_iter().collect();
+    checksums.sort();
+    Some(checksums)
+  }
+}
+
+/// Sends a tick every `tick_ms` until the room is removed. Ticks pause while nobody is in the room.
+pub async fn run(room: Weak<RwLock<Room>>, tick_ms: u32) {
+  let mut interval = tokio::time::interval(Duration::from_millis(tick_ms as u64));
+
+  loop {
+    interval.tick().await;
+
+    let room = match room.upgrade() {
+      Some(room) => room,
+      None => break,
+    };
+
+    let (msg, clients) = {
+      let mut r = room.write().await;
+      if r.clients.is_empty() {
+        continue;
+      }
+      let name = r.name.clone();
+      let msg = match r.lockstep.as_mut() {
+        Some(lockstep) => lockstep.advance(&name),
+        None => break,
+      };
+      (msg, r.clients.values().cloned().collect::<Vec<_>>())
+    };
+
+    for c in clients {
+      handler::send(&c, msg.clone()).await;
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn lockstep() -> Lockstep {
+    Lockstep::new(LockstepConfig { tick_ms: 50, input_delay: 2 })
+  }
+
+  fn inputs(msg: Message) -> Vec<(String, Vec<u8>)> {
+    match msg {
+      Message::Tick { inputs, .. } => inputs.into_iter().map(|input| (input.player, input.data)).collect(),
+      _ => panic!("not a tick"),
+    }
+  }
+
+  #[test]
+  fn clamp_bounds_client_configs() {
+    assert_eq!(clamp(LockstepConfig { tick_ms: 0, input_delay: u32::MAX }), LockstepConfig { tick_ms: MIN_TICK_MS, input_delay: MAX_INPUT_DELAY });
+    assert_eq!(clamp(LockstepConfig { tick_ms: u32::MAX, input_delay: 0 }), LockstepConfig { tick_ms: MAX_TICK_MS, input_delay: 0 });
+    assert_eq!(clamp(LockstepConfig { tick_ms: 50, input_delay: 2 }), LockstepConfig { tick_ms: 50, input_delay: 2 });
+  }
+
+  #[test]
+  fn add_input_orders_players_and_moves_late_inputs() {
+    let mut lockstep = lockstep();
+    assert!(lockstep.add_input("b", 0, vec![2]));
+    assert!(lockstep.add_input("a", 0, vec![1]));
+    assert_eq!(inputs(lockstep.advance("room")), vec![("a".to_owned(), vec![1]), ("b".to_owned(), vec![2])]);
+
+    // tick 0 was sent, so its input goes into the current tick
+    assert!(lockstep.add_input("a", 0, vec![3]));
+    assert_eq!(inputs(lockstep.advance("room")), vec![("a".to_owned(), vec![3])]);
+    assert!(inputs(lockstep.advance("room")).is_empty());
+  }
+
+  #[test]
+  fn add_input_rejects_ticks_too_far_ahead() {
+    let mut lockstep = lockstep();
+    assert!(lockstep.add_input("a", MAX_AHEAD, vec![1]));
+    assert!(!lockstep.add_input("a", MAX_AHEAD + 1, vec![1]));
+  }
+
+  #[test]
+  fn add_checksum_reports_disagreements_once_everyone_reported() {
+    let mut lockstep = lockstep();
+    lockstep.advance("room");
+    lockstep.advance("room");
+
+    assert_eq!(lockstep.add_checksum("a", 0, 7, 2), None);
+    assert_eq!(lockstep.add_checksum("b", 0, 7, 2), None);
+
+    assert_eq!(lockstep.add_checksum("b", 1, 8, 2), None);
+    assert_eq!(lockstep.add_checksum("a", 1, 9, 2), Some(vec![("a".to_owned(), 9), ("b".to_owned(), 8)]));
+  }
+
+  #[test]
+  fn add_checksum_ignores_unsent_and_old_ticks() {
+    let mut lockstep = lockstep();
+    assert_eq!(lockstep.add_checksum("a", 0, 1, 1), None);
+    assert!(lockstep.checksums.is_empty());
+
+    for _ in 0..CHECKSUM_HISTORY + 2 {
+      lockstep.advance("room");
+    }
+    assert_eq!(lockstep.add_checksum("a", 0, 1, 1), None);
+    assert!(lockstep.checksums.is_empty());
+  }
+
+  #[test]
+  fn add_checksum_forgets_ticks_a_player_never_reports() {
+    let mut lockstep = lockstep();
+    for tick in 0..CHECKSUM_HISTORY * 3 {
+      lockstep.advance("room");
+      assert_eq!(lockstep.add_checksum("a", tick, 1, 2), None);
+    }
+    assert!(lockstep.checksums.len() as u64 <= CHECKSUM_HISTORY);
+  }
+}
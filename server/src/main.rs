@@ -1,76 +1,406 @@
 use std::collections::{HashMap, HashSet};
-use std::sync::Arc;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 use env_logger::Env;
 use tokio::{
-    net::{
-        TcpStream,
-        TcpListener,
-    },
+    net::TcpListener,
+    task::JoinHandle,
     sync::{
-        mpsc::channel,
+        mpsc::{channel, Receiver, Sender},
         RwLock,
     }
 };
 
 
+mod access;
+mod assets;
 mod config;
 mod client;
+mod gateway;
+mod handler;
+mod lockstep;
+mod movement;
+mod room;
 
-use config::Config;
+use config::{Config, RoomConfig};
+use handler::Rejection;
 
 use shadow_of_truth_common as common;
 
 type RwClient = Arc<RwLock<client::Client>>;
 type RwClients = Arc<RwLock<HashMap<String, RwClient>>>;
+type Connection = (Sender<common::Message>, JoinHandle<()>);
+
+/// Seconds a room created by a client may stay empty before it is removed.
+const EMPTY_ROOM_TIMEOUT: u64 = 60;
 
 #[derive(Clone)]
 struct ServerContext {
     clients: RwClients,
-    rooms: Arc<RwLock<HashMap<String, RwClients>>>,
-    room_spawn_cache: Arc<RwLock<HashMap<String, HashMap<String, common::Message>>>>,
+    rooms: Arc<RwLock<HashMap<String, room::RwRoom>>>,
+    access: Arc<RwLock<access::AccessList>>,
+    /// Every open connection with its writer task, including clients which did not login yet.
+    connections: Arc<Mutex<Vec<Connection>>>,
+    /// Movement violations by user fingerprint, kept across reconnects.
+    violations: Arc<RwLock<HashMap<String, common::Offender>>>,
+    assets_dir: Arc<PathBuf>,
+    gateway_secret: Arc<Option<String>>,
+    started: Instant,
 }
 
 impl ServerContext {
-    async fn fill_spawn_cache(&self, msg: &common::Message) {
-        let mut cache = self.room_spawn_cache.write().await;
-        if let common::Message::Spawn{id, scene, ..} = msg {
-            let entry = cache.entry(scene.clone()).or_insert_with(|| HashMap::new());
-            entry.insert(id.clone(), msg.clone());
+    fn new(config: &Config) -> ServerContext {
+        ServerContext {
+            clients: Arc::new(RwLock::new(HashMap::new())),
+            rooms: Arc::new(RwLock::new(HashMap::new())),
+            access: Arc::new(RwLock::new(access::AccessList::default())),
+            connections: Arc::new(Mutex::new(Vec::new())),
+            violations: Arc::new(RwLock::new(HashMap::new())),
+            assets_dir: Arc::new(PathBuf::from(&config.assets_dir)),
+            gateway_secret: Arc::new(config.gateway_secret.clone()),
+            started: Instant::now(),
+        }
+    }
+
+    fn now(&self) -> u64 {
+        self.started.elapsed().as_millis() as u64
+    }
+
+    /// Milliseconds since the unix epoch, which agree between the workers of a gateway.
+    fn wall_clock(&self) -> u64 {
+        match SystemTime::now().duration_since(UNIX_EPOCH) {
+            Ok(time) => time.as_millis() as u64,
+            Err(_) => 0,
+        }
+    }
+
+    async fn create_room(&self, mut config: RoomConfig, persistent: bool) -> Result<(), Rejection> {
+        config.lockstep = config.lockstep.map(lockstep::clamp);
+        let manifest = assets::manifest(&self.assets_dir, &config.assets).await;
+
+        let mut rooms = self.rooms.write().await;
+        if rooms.contains_key(&config.name) {
+            return Err(Rejection::new(common::ErrorCode::RoomExists, format!("room {} already exists", config.name)));
+        }
+
+        log::info!("create room {}", config.name);
+        let name = config.name.clone();
+        let lockstep = config.lockstep;
+        let room = room::new(config, persistent, manifest);
+        if let Some(lockstep) = lockstep {
+            tokio::spawn(lockstep::run(Arc::downgrade(&room), lockstep.tick_ms));
+        }
+        rooms.insert(name, room);
+        Ok(())
+    }
+
+    async fn list_rooms(&self) -> Vec<common::RoomInfo> {
+        let rooms = self.rooms.read().await;
+        let mut infos = Vec::with_capacity(rooms.len());
+        for r in rooms.values() {
+            infos.push(r.read().await.info());
         }
+        infos
     }
 
-    async fn clean_spawn_cache(&self, msg: &common::Message) {
-        let mut cache = self.room_spawn_cache.write().await;
-        if let common::Message::Destroy{id, scene} = msg {
-            if let Some(entry) = cache.get_mut(scene) {
-                entry.remove(id);
+    async fn restore_rooms(&self, config: &Config) {
+        for r in config.rooms.iter() {
+            if let Err(e) = self.create_room(r.clone(), true).await {
+                log::warn!("{}", e.reason);
+            }
+        }
+
+        match config::load_rooms(&config.rooms_file) {
+            Ok(rooms) => {
+                for r in rooms {
+                    let exists = self.rooms.read().await.contains_key(&r.name);
+                    if !exists {
+                        if let Err(e) = self.create_room(r, true).await {
+                            log::warn!("{}", e.reason);
+                        }
+                    }
+                }
             }
+            Err(e) => log::error!("rooms {}", e),
+        }
+    }
+
+    /// Saves all persistent rooms which are not already part of the config.
+    async fn persist_rooms(&self, config: &Config) {
+        let mut persisted = Vec::new();
+        {
+            let rooms = self.rooms.read().await;
+            for r in rooms.values() {
+                let r = r.read().await;
+                if r.persistent && !config.rooms.iter().any(|c| c.name == r.name) {
+                    persisted.push(r.config());
+                }
+            }
+        }
+
+        if let Err(e) = config::save_rooms(&config.rooms_file, persisted) {
+            log::error!("rooms {}", e);
+        }
+    }
+
+    /// Removes the room if it is neither persistent nor has any players left.
+    async fn collect_room(&self, name: &str) {
+        let mut rooms = self.rooms.write().await;
+        let abandoned = match rooms.get(name) {
+            Some(r) => r.read().await.is_abandoned(),
+            None => false,
+        };
+
+        if abandoned {
+            log::info!("remove empty room {}", name);
+            rooms.remove(name);
+        }
+    }
+
+    /// Collects the room once nobody joined it for `EMPTY_ROOM_TIMEOUT`, rooms are otherwise only collected when the last player leaves.
+    fn collect_room_later(&self, name: String) {
+        let ctx = self.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_secs(EMPTY_ROOM_TIMEOUT)).await;
+            ctx.collect_room(&name).await;
+        });
+    }
+
+    /// Caches the spawn with its transform checked like an update, so entities can not spawn outside of the room bounds.
+    /// Returns the checked spawn, or None if the entity exists already.
+    async fn fill_spawn_cache(&self, client: &str, mut msg: common::Message) -> Option<common::Message> {
+        let (id, scene) = match &msg {
+            common::Message::Spawn{id, scene, ..} => (id.clone(), scene.clone()),
+            _ => return Some(msg),
+        };
+
+        let violated = {
+            let rooms = self.rooms.read().await;
+            let mut r = rooms.get(&scene)?.write().await;
+            // checked under the same lock as the insertion, so a duplicate can not start a track
+            if r.spawn_cache.contains_key(&id) {
+                return None;
+            }
+            // cached first, so the limits see the parents of the entity
+            r.spawn_cache.insert(id.clone(), msg.clone());
+            let mut violated = false;
+            if let common::Message::Spawn{transform: Some(t), ..} = &mut msg {
+                let (checked, v) = r.move_entity(&id, t, Instant::now());
+                *t = checked;
+                violated = v;
+            }
+            violated.then(|| r.limits.flag_after)
+        };
+
+        if let Some(flag_after) = violated {
+            self.add_violation(client, &id, flag_after).await;
+        }
+        Some(msg)
+    }
+
+    /// Destroys the entities `ids` in `scene` together with their children, whoever owns them.
+    async fn destroy_entities(&self, scene: &String, ids: Vec<String>) {
+        let mut destroyed = Vec::new();
+        {
+            let rooms = self.rooms.read().await;
+            if let Some(r) = rooms.get(scene) {
+                let mut r = r.write().await;
+                for id in ids {
+                    destroyed.extend(r.remove_entity(&id));
+                }
+                for c in r.clients.values() {
+                    let mut c = c.write().await;
+                    for id in destroyed.iter() {
+                        c.owned_spawns.remove(id);
+                    }
+                }
+            }
+        }
+
+        for id in destroyed {
+            self.relay_message(scene, &common::Message::Destroy{scene: scene.clone(), id}).await;
+        }
+    }
+
+    /// Clamps the transform of entity `id` to the movement limits of the room and returns the transform to relay.
+    /// Clients exceeding the limits repeatedly are flagged as offenders.
+    async fn check_movement(&self, client: &str, scene: &String, id: &str, t: &[f32; 16]) -> Option<[f32; 16]> {
+        let (t, violated, flag_after) = {
+            let rooms = self.rooms.read().await;
+            let mut r = rooms.get(scene)?.write().await;
+            let (t, violated) = r.move_entity(id, t, Instant::now());
+            (t, violated, r.limits.flag_after)
+        };
+
+        if violated {
+            self.add_violation(client, id, flag_after).await;
+        }
+
+        Some(t)
+    }
+
+    async fn add_violation(&self, client: &str, id: &str, flag_after: u32) {
+        let mut violations = self.violations.write().await;
+        let offender = violations.entry(client.to_owned())
+            .or_insert_with(|| common::Offender{id: client.to_owned(), violations: 0, flagged: false});
+        offender.violations += 1;
+        log::debug!("corrected movement of {} by {}", id, client);
+        if !offender.flagged && offender.violations >= flag_after {
+            offender.flagged = true;
+            log::warn!("flagged {} after {} movement violations", client, offender.violations);
+        }
+    }
+
+    async fn has_asset(&self, scene: &String, path: &String) -> bool {
+        let rooms = self.rooms.read().await;
+        match rooms.get(scene) {
+            Some(r) => r.read().await.manifest.iter().any(|asset| &asset.path == path),
+            None => false,
+        }
+    }
+
+    async fn is_lockstep(&self, scene: &String) -> bool {
+        let rooms = self.rooms.read().await;
+        match rooms.get(scene) {
+            Some(r) => r.read().await.lockstep.is_some(),
+            None => false,
+        }
+    }
+
+    async fn lockstep_input(&self, client: &str, scene: &String, tick: u64, data: Vec<u8>) -> Result<(), Rejection> {
+        let rooms = self.rooms.read().await;
+        let mut r = match rooms.get(scene) {
+            Some(r) => r.write().await,
+            None => return Err(Rejection::new(common::ErrorCode::UnknownRoom, format!("unknown room {}", scene))),
+        };
+        let lockstep = r.lockstep.as_mut()
+            .ok_or_else(|| Rejection::new(common::ErrorCode::WrongRoomMode, format!("room {} is not in lockstep", scene)))?;
+
+        if !lockstep.add_input(client, tick, data) {
+            return Err(Rejection::new(common::ErrorCode::InvalidTick, format!("tick {} is too far ahead of {}", tick, lockstep.tick)));
+        }
+        Ok(())
+    }
+
+    /// Tells the room when the checksums of a tick disagree.
+    async fn lockstep_checksum(&self, client: &str, scene: &String, tick: u64, checksum: u32) -> Result<(), Rejection> {
+        let desync = {
+            let rooms = self.rooms.read().await;
+            let mut r = match rooms.get(scene) {
+                Some(r) => r.write().await,
+                None => return Err(Rejection::new(common::ErrorCode::UnknownRoom, format!("unknown room {}", scene))),
+            };
+            let players = r.clients.len();
+            let lockstep = r.lockstep.as_mut()
+                .ok_or_else(|| Rejection::new(common::ErrorCode::WrongRoomMode, format!("room {} is not in lockstep", scene)))?;
+            lockstep.add_checksum(client, tick, checksum, players)
+        };
+
+        if let Some(checksums) = desync {
+            log::warn!("room {} desynchronized at tick {}", scene, tick);
+            self.relay_message(scene, &common::Message::Desync{scene: scene.clone(), tick, checksums}).await;
+        }
+        Ok(())
+    }
+
+    async fn has_entity(&self, scene: &String, id: &String) -> bool {
+        let rooms = self.rooms.read().await;
+        match rooms.get(scene) {
+            Some(r) => r.read().await.spawn_cache.contains_key(id),
+            None => false,
         }
     }
 
     async fn send_spawn_cache(&self, scene: String, client: RwClient) {
-        let cache = self.room_spawn_cache.read().await;
-        if let Some(entry) = cache.get(&scene) {
-            let c = client.read().await;
-            for msg in entry.values() {
-                c.tx.send(msg.clone()).await.unwrap();
+        let rooms = self.rooms.read().await;
+        if let Some(r) = rooms.get(&scene) {
+            let r = r.read().await;
+            if r.spawn_cache.is_empty() {
+                return;
             }
+            // one batch instead of a frame per spawn, so the cache is compressed as a whole
+            let messages = r.spawns();
+            handler::send(&client, common::Message::Batch{messages}).await;
         }
     }
 
     async fn relay_message(&self, scene: &String, msg: &common::Message) {
         let rooms = self.rooms.read().await;
-        if let Some(clients) = rooms.get(scene) {
-            let clients = clients.read().await;
+        if let Some(r) = rooms.get(scene) {
+            let r = r.read().await;
 
-            for c in clients.values() {
+            for c in r.clients.values() {
                 let client = c.read().await;
-                client.tx.send(msg.clone()).await.unwrap();
+                if let Err(e) = client.tx.send(msg.clone()).await {
+                    log::warn!("relay to {} {}", client.id, e);
+                }
             }
         }
     }
 
+    async fn join_room(&self, client: RwClient, scene: String, password: Option<String>) -> Result<(), Rejection> {
+        let id = client.read().await.id.clone();
+
+        {
+            // the room map stays locked until the client is registered, so the room can not be collected in between
+            let rooms = self.rooms.read().await;
+            let mut r = match rooms.get(&scene) {
+                Some(r) => r.write().await,
+                None => return Err(Rejection::new(common::ErrorCode::UnknownRoom, format!("unknown room {}", scene))),
+            };
+
+            if !r.check_password(&password) {
+                return Err(Rejection::new(common::ErrorCode::WrongPassword, format!("wrong password for room {}", scene)));
+            }
+            if r.is_full() && !r.clients.contains_key(&id) {
+                return Err(Rejection::new(common::ErrorCode::RoomFull, format!("room {} is full", scene)));
+            }
+
+            r.clients.insert(id, client.clone());
+            handler::send(&client, common::Message::Manifest{scene: scene.clone(), assets: r.manifest.clone()}).await;
+            if let Some(lockstep) = &r.lockstep {
+                let state = common::Message::LockstepState{scene: scene.clone(), tick: lockstep.tick, config: lockstep.config};
+                handler::send(&client, state).await;
+            }
+        }
+
+        let previous = {
+            let mut c = client.write().await;
+            std::mem::replace(&mut c.room, scene.clone())
+        };
+        if !previous.is_empty() && previous != scene {
+            self.leave_room(client.clone(), &previous).await;
+        }
+
+        self.send_spawn_cache(scene, client).await;
+        Ok(())
+    }
+
+    async fn leave_room(&self, client: RwClient, scene: &String) {
+        let (id, owned) = {
+            let mut c = client.write().await;
+            let owned: Vec<String> = c.owned_spawns.drain().collect();
+            (c.id.clone(), owned)
+        };
+
+        {
+            let rooms = self.rooms.read().await;
+            if let Some(r) = rooms.get(scene) {
+                let mut r = r.write().await;
+                // a newer session of the same player may have taken the place already
+                if r.clients.get(&id).is_some_and(|c| Arc::ptr_eq(c, &client)) {
+                    r.clients.remove(&id);
+                }
+            }
+        }
+
+        self.destroy_entities(scene, owned).await;
+
+        self.collect_room(scene).await;
+    }
+
     async fn disconnect_client(&self, client: RwClient) {
         let (id, room) = {
             let mut c = client.write().await;
@@ -80,102 +410,121 @@ impl ServerContext {
         
         {
             let mut clients = self.clients.write().await;
-            clients.remove(&id);
-        }
-
-        {
-            let rooms = self.rooms.read().await;
-            if let Some(clients) = rooms.get(&room) {
-                let mut clients = clients.write().await;
+            // a second login with the same key replaced this session, the new one stays
+            if clients.get(&id).is_some_and(|c| Arc::ptr_eq(c, &client)) {
                 clients.remove(&id);
             }
         }
 
-        {
-            let mut cache = self.room_spawn_cache.write().await;
-            if let Some(r) = cache.get_mut(&room) {
-                let client = client.read().await;
-                for id in client.owned_spawns.iter() {
-                    self.relay_message(&room, &common::Message::Destroy{scene: room.clone(), id: id.clone()}).await;
-                    r.remove(id);
+        if !room.is_empty() {
+            self.leave_room(client, &room).await;
+        }
+    }
+}
+
+/// Tells every connection about the shutdown and waits until their writer queues are flushed.
+async fn notify_shutdown(connections: &Mutex<Vec<Connection>>, reason: String, reconnect_after: Option<u64>) {
+    let connections: Vec<Connection> = connections.lock().unwrap().drain(..).collect();
+    let msg = common::Message::ServerShutdown{reason, reconnect_after};
+
+    for (tx, _) in connections.iter() {
+        if let Err(e) = tx.send(msg.clone()).await {
+            log::warn!("shutdown notification {}", e);
+        }
+    }
+
+    for (_, writer) in connections {
+        if let Err(e) = writer.await {
+            log::warn!("writer {}", e);
+        }
+    }
+}
+
+/// Writes the queued messages of a connection until the queue closes or the server shuts down.
+fn spawn_writer(mut write: Box<dyn common::transport::Writer>, mut rx: Receiver<common::Message>) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        while let Some(msg) = rx.recv().await {
+            let is_shutdown = matches!(msg, common::Message::ServerShutdown{..});
+            let codec = match msg {
+                common::Message::CodecChanged{codec} => Some(codec),
+                _ => None,
+            };
+            let compression = match msg {
+                common::Message::CompressionChanged{threshold} => Some(threshold),
+                _ => None,
+            };
+
+            if let Err(e) = write.write(msg).await {
+                log::error!("writer {}", e.to_string());
+                break;
+            }
+
+            if let Some(codec) = codec {
+                write.set_codec(codec);
+            }
+            if let Some(threshold) = compression {
+                write.set_compression(threshold);
+            }
+
+            if is_shutdown {
+                if let Err(e) = write.shutdown().await {
+                    log::warn!("writer shutdown {}", e);
                 }
+                break;
             }
         }
-    }
+    })
 }
 
-fn handle_stream(
-    stream: TcpStream, 
+fn handle_connection(
+    transport: common::transport::Transport,
     ctx: ServerContext,
 ) {
-    let (mut read, mut write) = stream.into_split();
-    let (tx, mut rx) = channel(20);
+    let (mut read, write) = transport;
+    let (tx, rx) = channel(20);
     let client = Arc::new(RwLock::new(client::Client {
         id: "".to_owned(),
         room: "".to_owned(),
         owned_spawns: HashSet::new(),
         state: client::ClientState::Greeting,
-        tx: tx,
+        tx: tx.clone(),
     }));
 
+    let connections = ctx.connections.clone();
     tokio::spawn(async move {
         loop {
-            match common::async_read(&mut read).await {
+            match read.read().await {
                 Ok(Some(msg)) => {
                     match &msg {
-                        &common::Message::TransformUpdate{..} => {}
+                        &common::Message::TransformUpdate{..} | &common::Message::Ping{..} => {}
                         m @ _ => {
                             log::debug!("{:?}", m);
                         }
                     }
-                    match msg {
-                        common::Message::Login{id} => {
-                            {
-                                let mut client = client.write().await;
-                                client.id = id.clone();
-                            }
-                            let mut clients = ctx.clients.write().await;
-                            clients.insert(id, client.clone());
-                        }
-                        common::Message::Join{scene} => {
-                            let id = {
-                                let mut c = client.write().await;
-                                c.room = scene.clone();
-                                c.id.clone()
-                            };
-                            let mut rooms = ctx.rooms.write().await;
-                            let entry = rooms.entry(scene.clone()).or_insert_with(|| Arc::new(RwLock::new(HashMap::new())));
-                            let mut room = entry.write().await; 
-                            room.insert(id, client.clone());
-
-                            ctx.send_spawn_cache(scene, client.clone()).await;
-                        }
-                        common::Message::Spawn{id, scene, drawable, behavior} => {
-                            let spawn = common::Message::Spawn{id: id.clone(), scene: scene.clone(), drawable: drawable, behavior: behavior};
-                            {
-                                let mut client = client.write().await;
-                                client.owned_spawns.insert(id);
-                            }
-                            ctx.fill_spawn_cache(&spawn).await;
-                            ctx.relay_message(&scene, &spawn).await;
-                        }
-                        common::Message::Destroy{id, scene} => {
-                            let destroy = common::Message::Destroy{id: id.clone(), scene: scene.clone()};
-                            {
-                                let mut client = client.write().await;
-                                client.owned_spawns.remove(&id);
-                            }
-                            ctx.clean_spawn_cache(&destroy).await;
-                            ctx.relay_message(&scene, &destroy).await;
-                        }
-                        common::Message::TransformUpdate{scene, id, t} => {
-                            let msg = common::Message::TransformUpdate{scene: scene.clone(), id, t};
-                            ctx.relay_message(&scene, &msg).await;
-                        }
-                        _ => {}
+                    let (request, msg) = match msg {
+                        common::Message::Request{id, message} => (Some(id), *message),
+                        m => (None, m),
+                    };
+                    if let common::Message::SetCodec{codec} = msg {
+                        // the codec of the reader changes right away, the writer follows after sending the acknowledgement
+                        read.set_codec(codec);
+                        handler::send(&client, common::Message::CodecChanged{codec}).await;
+                        continue;
+                    }
+                    if let common::Message::SetCompression{threshold} = msg {
+                        handler::send(&client, common::Message::CompressionChanged{threshold}).await;
+                        continue;
+                    }
+                    if let Err(rejection) = handler::handle(&ctx, &client, msg).await {
+                        log::warn!("reject {:?}: {}", rejection.code, rejection.reason);
+                        handler::send(&client, rejection.reply(request)).await;
                     }
                 }
                 Ok(None) => { break }
+                Err(common::ReadError::Decode(e)) => {
+                    let rejection = Rejection::new(common::ErrorCode::UnknownMessage, e);
+                    handler::send(&client, rejection.reply(None)).await;
+                }
                 Err(e) => {
                     log::error!("read {}", e);
                     break;
@@ -185,55 +534,167 @@ fn handle_stream(
         ctx.disconnect_client(client).await;
     });
 
-    tokio::spawn(async move {
-        while let Some(msg) = rx.recv().await {
-            if let Err(e) = common::async_write(&mut write, msg).await {
-                log::error!("writer {}", e.to_string());
-                break;
-            }
-        }
-    });
+    let writer = spawn_writer(write, rx);
+
+    let mut connections = connections.lock().unwrap();
+    connections.retain(|(_, writer)| !writer.is_finished());
+    connections.push((tx, writer));
 }
 
 
 
-async fn listen(config: Config) -> Result<(), Box<dyn std::error::Error>> {
+async fn listen(config: Config, ctx: ServerContext) -> Result<(), Box<dyn std::error::Error>> {
     let addr = format!("127.0.0.1:{}", config.port);
     let filename = std::path::Path::new(&config.private_key);
     // let private_key = optain_private_key(&filename);
     let listener = TcpListener::bind(addr).await?;
-    let ctx = ServerContext {
-        clients: Arc::new(RwLock::new(HashMap::new())),
-        rooms: Arc::new(RwLock::new(HashMap::new())),
-        room_spawn_cache: Arc::new(RwLock::new(HashMap::new())),
-    };
-
     log::info!("listen on {}", listener.local_addr()?);
     loop {
         match listener.accept().await {
             Ok((stream, addr)) => {
                 log::info!("connection from {:?}", addr);
-                handle_stream(stream, ctx.clone());
+                handle_connection(common::transport::tcp(stream), ctx.clone());
             }
             Err(e) => log::error!("listener: {}", e.to_string())
         }
     }
 }
 
+async fn listen_websocket(port: u16, ctx: ServerContext) -> Result<(), Box<dyn std::error::Error>> {
+    let listener = TcpListener::bind(format!("127.0.0.1:{}", port)).await?;
+    log::info!("listen for websockets on {}", listener.local_addr()?);
+    loop {
+        match listener.accept().await {
+            Ok((stream, addr)) => {
+                log::info!("websocket connection from {:?}", addr);
+                let ctx = ctx.clone();
+                tokio::spawn(async move {
+                    match common::transport::accept_websocket(stream).await {
+                        Ok(transport) => handle_connection(transport, ctx),
+                        Err(e) => log::error!("websocket handshake: {}", e),
+                    }
+                });
+            }
+            Err(e) => log::error!("websocket listener: {}", e)
+        }
+    }
+}
+
+/// Only sockets are removed from the configured path, any other file there makes the bind fail.
+#[cfg(unix)]
+fn is_socket(path: &str) -> bool {
+    use std::os::unix::fs::FileTypeExt;
+
+    std::fs::symlink_metadata(path).map(|m| m.file_type().is_socket()).unwrap_or(false)
+}
+
+#[cfg(not(unix))]
+fn is_socket(_: &str) -> bool {
+    false
+}
+
+#[cfg(unix)]
+async fn listen_unix(path: String, ctx: ServerContext) -> Result<(), Box<dyn std::error::Error>> {
+    // a socket file left over from a previous run would make the bind fail
+    if is_socket(&path) {
+        std::fs::remove_file(&path)?;
+    }
+    let listener = tokio::net::UnixListener::bind(&path)?;
+    log::info!("listen on {}", path);
+    loop {
+        match listener.accept().await {
+            Ok((stream, _)) => {
+                log::info!("connection on {}", path);
+                handle_connection(common::transport::unix(stream), ctx.clone());
+            }
+            Err(e) => log::error!("unix listener: {}", e)
+        }
+    }
+}
+
+fn spawn_listeners(config: &Config, ctx: &ServerContext) -> Vec<JoinHandle<()>> {
+    let mut listeners = Vec::new();
+
+    {
+        let config = config.clone();
+        let ctx = ctx.clone();
+        listeners.push(tokio::spawn(async move {
+            if let Err(e) = listen(config, ctx).await {
+                log::error!("{}", e.to_string());
+            }
+        }));
+    }
+
+    if let Some(port) = config.websocket_port {
+        let ctx = ctx.clone();
+        listeners.push(tokio::spawn(async move {
+            if let Err(e) = listen_websocket(port, ctx).await {
+                log::error!("websocket {}", e);
+            }
+        }));
+    }
+
+    #[cfg(unix)]
+    {
+        if let Some(path) = config.unix_socket.clone() {
+            let ctx = ctx.clone();
+            listeners.push(tokio::spawn(async move {
+                if let Err(e) = listen_unix(path, ctx).await {
+                    log::error!("unix socket {}", e);
+                }
+            }));
+        }
+    }
+
+    listeners
+}
+
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let env = Env::default().default_filter_or("debug");
     env_logger::Builder::from_env(env).init();
 
-    match config::load() {
+    // several servers on one machine, like the workers of a gateway, need their own config
+    let path = std::env::args().nth(1).unwrap_or_else(|| "data/config.toml".to_owned());
+
+    match config::load(&path) {
         Ok(config) => {
-            tokio::spawn(async move {
-                if let Err(e) = listen(config).await {
-                    log::error!("{}", e.to_string());
+            if let Some(gateway) = config.gateway.clone() {
+                if let Err(e) = gateway::run(&config, gateway).await {
+                    log::error!("gateway {}", e);
                 }
-            });
+                return Ok(());
+            }
+
+            let ctx = ServerContext::new(&config);
+            ctx.restore_rooms(&config).await;
+
+            match access::load(&config.access_file) {
+                Ok(access) => *ctx.access.write().await = access,
+                Err(e) => log::error!("access list {}", e),
+            }
+
+            let listeners = spawn_listeners(&config, &ctx);
 
             tokio::signal::ctrl_c().await?;
+            log::info!("shutting down");
+            for listener in listeners {
+                listener.abort();
+            }
+
+            let timeout = Duration::from_secs(config.shutdown_timeout);
+            let notify = notify_shutdown(&ctx.connections, "server is shutting down".to_owned(), config.reconnect_after);
+            if tokio::time::timeout(timeout, notify).await.is_err() {
+                log::warn!("clients were not flushed within {}s", config.shutdown_timeout);
+            }
+
+            ctx.persist_rooms(&config).await;
+
+            if let Some(path) = config.unix_socket.as_ref().filter(|path| is_socket(path)) {
+                if let Err(e) = std::fs::remove_file(path) {
+                    log::warn!("unix socket {}", e);
+                }
+            }
         }
         Err(e) => log::error!("{}", e.to_string()),
     }
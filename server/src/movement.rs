@@ -0,0 +1,253 @@
+use std::time::{Duration, Instant};
+
+use serde::{Serialize, Deserialize};
+
+/// Updates arrive with network jitter, so the limits are only enforced beyond this factor.
+const TOLERANCE: f32 = 1.25;
+
+/// Shortest time between two updates used for the limits, clients send about every 50ms.
+/// Updates arriving sooner borrow it from the following ones, so the movement of an entity can run
+/// at most this far ahead of the server.
+const MIN_DELTA: f32 = 0.05;
+
+/// Longest time between two updates used for the limits, so an entity can not save up distance by staying silent.
+const MAX_DELTA: f32 = 1.0;
+
+fn default_flag_after() -> u32 {
+  10
+}
+
+/// Limits for the entities of a room, every unset limit is not enforced.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct MovementLimits {
+  /// Units per second.
+  pub max_speed: Option<f32>,
+  /// Units per second squared.
+  pub max_acceleration: Option<f32>,
+  /// Lowest and highest corner of the box entities have to stay in.
+  pub bounds: Option<[[f32; 3]; 2]>,
+  /// Violations after which a client is flagged as offender.
+  #[serde(default = "default_flag_after")]
+  pub flag_after: u32,
+}
+
+impl Default for MovementLimits {
+  fn default() -> MovementLimits {
+    MovementLimits {
+      max_speed: None,
+      max_acceleration: None,
+      bounds: None,
+      flag_after: default_flag_after(),
+    }
+  }
+}
+
+/// Last accepted movement of an entity.
+pub struct Track {
+  position: [f32; 3],
+  velocity: [f32; 3],
+  /// How far the movement is accounted for, up to `MIN_DELTA` ahead of the server.
+  time: Instant,
+}
+
+impl Track {
+  /// Seconds of movement the update at `now` may cover and up to when the movement is accounted for then.
+  fn advance(&self, now: Instant) -> (f32, Instant) {
+    let since = now.saturating_duration_since(self.time).as_secs_f32() - self.time.saturating_duration_since(now).as_secs_f32();
+    let saved = since.min(MAX_DELTA);
+    let dt = saved.max(MIN_DELTA).min(saved + MIN_DELTA).max(0.0);
+    (dt, now + Duration::from_secs_f32(dt - saved))
+  }
+}
+
+fn length(v: &[f32; 3]) -> f32 {
+  (v[0] * v[0] + v[1] * v[1] + v[2] * v[2]).sqrt()
+}
+
+fn scaled(v: &[f32; 3], f: f32) -> [f32; 3] {
+  [v[0] * f, v[1] * f, v[2] * f]
+}
+
+/// Product of the column major transforms `a` and `b`.
+pub fn multiply(a: &[f32; 16], b: &[f32; 16]) -> [f32; 16] {
+  let mut out = [0.0; 16];
+  for column in 0..4 {
+    for row in 0..4 {
+      out[column * 4 + row] = (0..4).map(|k| a[k * 4 + row] * b[column * 4 + k]).sum();
+    }
+  }
+  out
+}
+
+/// Moves the local transform `t` below `parent` by `offset` in world space,
+/// leaves it unchanged when the parent collapses space.
+pub fn shift(parent: &[f32; 16], t: &[f32; 16], offset: [f32; 3]) -> [f32; 16] {
+  let m = |row: usize, column: usize| parent[column * 4 + row];
+  let cofactor = |row: usize, column: usize| {
+    let (r0, r1) = ((row + 1) % 3, (row + 2) % 3);
+    let (c0, c1) = ((column + 1) % 3, (column + 2) % 3);
+    m(r0, c0) * m(r1, c1) - m(r0, c1) * m(r1, c0)
+  };
+  let det = m(0, 0) * cofactor(0, 0) + m(0, 1) * cofactor(0, 1) + m(0, 2) * cofactor(0, 2);
+  if det.abs() < f32::EPSILON {
+    return *t;
+  }
+
+  let mut shifted = *t;
+  for row in 0..3 {
+    // the inverse is the transposed cofactor matrix divided by the determinant
+    shifted[12 + row] += (0..3).map(|k| cofactor(k, row) * offset[k]).sum::<f32>() / det;
+  }
+  shifted
+}
+
+fn clamp_length(v: [f32; 3], max: f32) -> ([f32; 3], bool) {
+  let l = length(&v);
+  if l > max {
+    (scaled(&v, max / l), true)
+  }
+  else {
+    (v, false)
+  }
+}
+
+impl MovementLimits {
+  /// Checks the world transform `t` against the limits and the previous movement `last`.
+  /// Returns the transform clamped to the limits, the movement to check the next update against
+  /// and whether the transform had to be corrected.
+  pub fn apply(&self, last: Option<&Track>, t: &[f32; 16], now: Instant) -> ([f32; 16], Track, bool) {
+    let mut position = [t[12], t[13], t[14]];
+    let mut velocity = [0.0; 3];
+    let mut violated = false;
+    let mut time = now;
+
+    if let Some(last) = last {
+      let (dt, accounted) = last.advance(now);
+      time = accounted;
+      let mut displacement = [position[0] - last.position[0], position[1] - last.position[1], position[2] - last.position[2]];
+
+      if dt <= 0.0 {
+        // the entity is as far ahead of the server as allowed, so it has to wait until the server catches up
+        velocity = last.velocity;
+        if (self.max_speed.is_some() || self.max_acceleration.is_some()) && length(&displacement) > 0.0 {
+          displacement = [0.0; 3];
+          violated = true;
+        }
+      }
+      else {
+        if let Some(max_speed) = self.max_speed {
+          let (d, clamped) = clamp_length(displacement, max_speed * dt * TOLERANCE);
+          displacement = d;
+          violated |= clamped;
+        }
+
+        velocity = scaled(&displacement, 1.0 / dt);
+
+        if let Some(max_acceleration) = self.max_acceleration {
+          let change = [velocity[0] - last.velocity[0], velocity[1] - last.velocity[1], velocity[2] - last.velocity[2]];
+          let (change, clamped) = clamp_length(change, max_acceleration * dt * TOLERANCE);
+          if clamped {
+            velocity = [last.velocity[0] + change[0], last.velocity[1] + change[1], last.velocity[2] + change[2]];
+            displacement = scaled(&velocity, dt);
+            violated = true;
+          }
+        }
+      }
+
+      position = [last.position[0] + displacement[0], last.position[1] + displacement[1], last.position[2] + displacement[2]];
+    }
+
+    if let Some([min, max]) = self.bounds {
+      for i in 0..3 {
+        let clamped = position[i].max(min[i]).min(max[i]);
+        if clamped != position[i] {
+          position[i] = clamped;
+          velocity[i] = 0.0;
+          violated = true;
+        }
+      }
+    }
+
+    let mut corrected = *t;
+    corrected[12] = position[0];
+    corrected[13] = position[1];
+    corrected[14] = position[2];
+
+    (corrected, Track { position, velocity, time }, violated)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn at(x: f32) -> [f32; 16] {
+    let mut t = [0.0; 16];
+    t[12] = x;
+    t
+  }
+
+  #[test]
+  fn apply_caps_the_time_between_updates() {
+    let limits = MovementLimits { max_speed: Some(1.0), ..MovementLimits::default() };
+    let start = Instant::now();
+    let (_, track, _) = limits.apply(None, &at(0.0), start);
+
+    let (t, _, violated) = limits.apply(Some(&track), &at(100.0), start + Duration::from_secs(100));
+    assert!(violated);
+    assert_eq!(t[12], MAX_DELTA * TOLERANCE);
+  }
+
+  #[test]
+  fn apply_limits_quick_updates() {
+    let limits = MovementLimits { max_speed: Some(1.0), ..MovementLimits::default() };
+    let start = Instant::now();
+    let (_, mut track, _) = limits.apply(None, &at(0.0), start);
+
+    let mut x = 0.0;
+    for i in 1..=100 {
+      let (t, next, _) = limits.apply(Some(&track), &at(i as f32), start);
+      track = next;
+      x = t[12];
+    }
+    // all updates together may not move further than a single one
+    assert!(x <= MIN_DELTA * TOLERANCE + f32::EPSILON);
+
+    // time passing allows movement again
+    let (t, _, _) = limits.apply(Some(&track), &at(100.0), start + Duration::from_millis(500));
+    assert!(t[12] > x && t[12] <= 0.55 * TOLERANCE + f32::EPSILON);
+  }
+
+  #[test]
+  fn apply_lets_early_updates_borrow_time() {
+    let limits = MovementLimits { max_speed: Some(1.0), ..MovementLimits::default() };
+    let start = Instant::now();
+    let (_, track, _) = limits.apply(None, &at(0.0), start);
+
+    // jitter delivers the first update after 30ms and the second after 100ms, both moved 50ms
+    let (_, track, violated) = limits.apply(Some(&track), &at(0.05), start + Duration::from_millis(30));
+    assert!(!violated);
+    let (t, _, violated) = limits.apply(Some(&track), &at(0.1), start + Duration::from_millis(100));
+    assert!(!violated);
+    assert_eq!(t[12], 0.1);
+  }
+
+  #[test]
+  fn shift_moves_in_world_space() {
+    // rotated by 90 degrees around z, scaled by 2 and moved
+    let mut parent = [0.0; 16];
+    parent[1] = 2.0;
+    parent[4] = -2.0;
+    parent[10] = 2.0;
+    parent[12] = 3.0;
+    parent[15] = 1.0;
+    let mut t = at(1.0);
+    t[15] = 1.0;
+
+    let before = multiply(&parent, &t);
+    let after = multiply(&parent, &shift(&parent, &t, [1.0, 2.0, 3.0]));
+    for i in 0..3 {
+      assert!((after[12 + i] - before[12 + i] - [1.0, 2.0, 3.0][i]).abs() < 1e-5);
+    }
+  }
+}
@@ -0,0 +1,315 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use std::time::Instant;
+
+use tokio::sync::RwLock;
+
+use shadow_of_truth_common::{AssetInfo, Message, RoomInfo};
+
+use crate::RwClient;
+use crate::config::RoomConfig;
+use crate::lockstep::Lockstep;
+use crate::movement::{self, MovementLimits, Track};
+
+const IDENTITY: [f32; 16] = [1.0, 0.0, 0.0, 0.0, 0.0, 1.0, 0.0, 0.0, 0.0, 0.0, 1.0, 0.0, 0.0, 0.0, 0.0, 1.0];
+
+pub struct Room {
+  pub name: String,
+  /// Zero means the room accepts any number of players.
+  pub max_players: u32,
+  pub password: Option<String>,
+  /// Persistent rooms are kept alive even when the last player leaves.
+  pub persistent: bool,
+  pub clients: HashMap<String, RwClient>,
+  pub spawn_cache: HashMap<String, Message>,
+  pub limits: MovementLimits,
+  pub manifest: Vec<AssetInfo>,
+  /// Last accepted movement of every entity which sent a transform.
+  pub tracks: HashMap<String, Track>,
+  pub lockstep: Option<Lockstep>,
+}
+
+impl Room {
+  pub fn new(config: RoomConfig, persistent: bool, manifest: Vec<AssetInfo>) -> Room {
+    Room {
+      name: config.name,
+      max_players: config.max_players,
+      password: config.password,
+      persistent,
+      clients: HashMap::new(),
+      spawn_cache: HashMap::new(),
+      limits: config.limits,
+      manifest,
+      tracks: HashMap::new(),
+      lockstep: config.lockstep.map(Lockstep::new),
+    }
+  }
+
+  pub fn info(&self) -> RoomInfo {
+    RoomInfo {
+      name: self.name.clone(),
+      players: self.clients.len() as u32,
+      max_players: self.max_players,
+      has_password: self.password.is_some(),
+      persistent: self.persistent,
+      lockstep: self.lockstep.as_ref().map(|lockstep| lockstep.config),
+    }
+  }
+
+  pub fn config(&self) -> RoomConfig {
+    RoomConfig {
+      name: self.name.clone(),
+      max_players: self.max_players,
+      password: self.password.clone(),
+      limits: self.limits.clone(),
+      assets: self.manifest.iter().map(|asset| asset.path.clone()).collect(),
+      lockstep: self.lockstep.as_ref().map(|lockstep| lockstep.config),
+    }
+  }
+
+  pub fn is_full(&self) -> bool {
+    self.max_players > 0 && self.clients.len() as u32 >= self.max_players
+  }
+
+  pub fn check_password(&self, password: &Option<String>) -> bool {
+    match &self.password {
+      Some(expected) => password.as_ref() == Some(expected),
+      None => true,
+    }
+  }
+
+  /// Checks the transform `t` of the cached entity `id` against the movement limits and keeps the corrected one,
+  /// returns it and whether it had to be corrected.
+  pub fn move_entity(&mut self, id: &str, t: &[f32; 16], now: Instant) -> ([f32; 16], bool) {
+    // the limits hold in the room, where the parents move their children as well
+    let parent = self.parent_transform(id);
+    let world = movement::multiply(&parent, t);
+    let (checked, track, violated) = self.limits.apply(self.tracks.get(id), &world, now);
+    self.tracks.insert(id.to_owned(), track);
+
+    let t = if violated {
+      movement::shift(&parent, t, [checked[12] - world[12], checked[13] - world[13], checked[14] - world[14]])
+    }
+    else {
+      *t
+    };
+    if let Some(Message::Spawn{transform, ..}) = self.spawn_cache.get_mut(id) {
+      *transform = Some(t);
+    }
+    (t, violated)
+  }
+
+  /// Transform from the space of `id` into the room, the product of the transforms of its parents.
+  fn parent_transform(&self, id: &str) -> [f32; 16] {
+    let mut t = IDENTITY;
+    let mut visited = HashSet::new();
+    let mut current = id;
+    while let Some(Message::Spawn{parent: Some(p), ..}) = self.spawn_cache.get(current) {
+      // an edited rooms file can contain cycles
+      if !visited.insert(p.as_str()) {
+        break;
+      }
+      if let Some(Message::Spawn{transform: Some(pt), ..}) = self.spawn_cache.get(p) {
+        t = movement::multiply(pt, &t);
+      }
+      current = p;
+    }
+    t
+  }
+
+  /// Every cached spawn with the parents before their children.
+  pub fn spawns(&self) -> Vec<Message> {
+    let mut ordered: Vec<Message> = Vec::with_capacity(self.spawn_cache.len());
+    let mut sent = HashSet::new();
+    let mut remaining: Vec<&Message> = self.spawn_cache.values().collect();
+
+    while !remaining.is_empty() {
+      let count = remaining.len();
+      remaining.retain(|msg| {
+        let (id, ready) = match msg {
+          Message::Spawn{id, parent: Some(p), ..} => (id, sent.contains(p) || !self.spawn_cache.contains_key(p)),
+          Message::Spawn{id, parent: None, ..} => (id, true),
+          _ => return false,
+        };
+        if ready {
+          sent.insert(id.clone());
+          ordered.push((*msg).clone());
+        }
+        !ready
+      });
+      if remaining.len() == count {
+        // only cycles are left, which spawns can not create but an edited rooms file can
+        ordered.extend(remaining.drain(..).cloned());
+      }
+    }
+
+    ordered
+  }
+
+  /// Removes `id` and all its descendants, returns their ids with the children before their parents.
+  pub fn remove_entity(&mut self, id: &str) -> Vec<String> {
+    let mut children: HashMap<&str, Vec<&str>> = HashMap::new();
+    for (child, msg) in self.spawn_cache.iter() {
+      if let Message::Spawn{parent: Some(p), ..} = msg {
+        children.entry(p.as_str()).or_default().push(child.as_str());
+      }
+    }
+
+    // breadth first puts the parents before their children, the visited set stops at cycles
+    let mut visited = HashSet::new();
+    visited.insert(id);
+    let mut order = vec![id];
+    let mut i = 0;
+    while i < order.len() {
+      for child in children.get(order[i]).into_iter().flatten() {
+        if visited.insert(*child) {
+          order.push(*child);
+        }
+      }
+      i += 1;
+    }
+    let order: Vec<String> = order.into_iter().rev().map(|id| id.to_owned()).collect();
+
+    let mut removed = Vec::new();
+    for id in order {
+      if self.spawn_cache.remove(&id).is_some() {
+        self.tracks.remove(&id);
+        removed.push(id);
+      }
+    }
+
+    removed
+  }
+
+  pub fn is_abandoned(&self) -> bool {
+    !self.persistent && self.clients.is_empty()
+  }
+}
+
+pub type RwRoom = Arc<RwLock<Room>>;
+
+pub fn new(config: RoomConfig, persistent: bool, manifest: Vec<AssetInfo>) -> RwRoom {
+  Arc::new(RwLock::new(Room::new(config, persistent, manifest)))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn room() -> Room {
+    let config = RoomConfig {
+      name: "test".to_owned(),
+      max_players: 0,
+      password: None,
+      limits: MovementLimits::default(),
+      assets: Vec::new(),
+      lockstep: None,
+    };
+    Room::new(config, false, Vec::new())
+  }
+
+  fn spawn(room: &mut Room, id: &str, parent: Option<&str>) {
+    let msg = Message::Spawn {
+      id: id.to_owned(),
+      scene: room.name.clone(),
+      drawable: "cube".to_owned(),
+      behavior: None,
+      parent: parent.map(|p| p.to_owned()),
+      transform: None,
+      material: None,
+      owner: None,
+    };
+    room.spawn_cache.insert(id.to_owned(), msg);
+  }
+
+  fn place(room: &mut Room, id: &str, t: [f32; 16]) {
+    if let Some(Message::Spawn{transform, ..}) = room.spawn_cache.get_mut(id) {
+      *transform = Some(t);
+    }
+  }
+
+  fn ids(spawns: &[Message]) -> Vec<String> {
+    spawns.iter()
+      .filter_map(|msg| match msg {
+        Message::Spawn{id, ..} => Some(id.clone()),
+        _ => None,
+      })
+      .collect()
+  }
+
+  fn position(ids: &[String], id: &str) -> usize {
+    ids.iter().position(|i| i == id).unwrap()
+  }
+
+  #[test]
+  fn spawns_puts_parents_first() {
+    let mut room = room();
+    spawn(&mut room, "leaf", Some("child"));
+    spawn(&mut room, "child", Some("root"));
+    spawn(&mut room, "root", None);
+    spawn(&mut room, "orphan", Some("gone"));
+
+    let ids = ids(&room.spawns());
+    assert_eq!(ids.len(), 4);
+    assert!(position(&ids, "root") < position(&ids, "child"));
+    assert!(position(&ids, "child") < position(&ids, "leaf"));
+  }
+
+  #[test]
+  fn spawns_keeps_cycles() {
+    let mut room = room();
+    spawn(&mut room, "a", Some("b"));
+    spawn(&mut room, "b", Some("a"));
+
+    assert_eq!(room.spawns().len(), 2);
+  }
+
+  #[test]
+  fn remove_entity_removes_children_first() {
+    let mut room = room();
+    spawn(&mut room, "root", None);
+    spawn(&mut room, "child", Some("root"));
+    spawn(&mut room, "leaf", Some("child"));
+    spawn(&mut room, "other", None);
+
+    let removed = room.remove_entity("root");
+    assert_eq!(removed, vec!["leaf", "child", "root"]);
+    assert_eq!(room.spawn_cache.len(), 1);
+  }
+
+  #[test]
+  fn remove_entity_stops_at_cycles() {
+    let mut room = room();
+    spawn(&mut room, "a", Some("b"));
+    spawn(&mut room, "b", Some("a"));
+    spawn(&mut room, "c", Some("c"));
+
+    let mut removed = room.remove_entity("a");
+    removed.sort();
+    assert_eq!(removed, vec!["a", "b"]);
+    assert_eq!(room.remove_entity("c"), vec!["c"]);
+    assert!(room.spawn_cache.is_empty());
+  }
+
+  #[test]
+  fn move_entity_limits_the_position_in_the_room() {
+    let mut room = room();
+    room.limits.bounds = Some([[-5.0; 3], [5.0; 3]]);
+    spawn(&mut room, "parent", None);
+    spawn(&mut room, "child", Some("parent"));
+
+    let mut parent = IDENTITY;
+    parent[0] = 2.0;
+    parent[5] = 2.0;
+    parent[10] = 2.0;
+    parent[12] = 4.0;
+    place(&mut room, "parent", parent);
+
+    // 1 below the parent is 6 in the room, which is outside of the bounds
+    let mut child = IDENTITY;
+    child[12] = 1.0;
+    let (t, violated) = room.move_entity("child", &child, Instant::now());
+    assert!(violated);
+    assert_eq!(t[12], 0.5);
+  }
+}